@@ -0,0 +1,102 @@
+// What a split hotkey spends talking to the X server before the window moves: the requests
+// do_window_command made before get_window_info, each waiting on its reply in turn, against
+// get_window_info. The work area and _NET_SUPPORTED come from the warmed cache on both sides,
+// so only the window lookups are compared. The window manager is assumed to publish
+// _NET_FRAME_EXTENTS, without it the old path also walked the window tree every time.
+// Needs a running X server and a focused window: cargo bench --bench hotkey_latency
+use stateful_split_screen::socket::get_display;
+use stateful_split_screen::xcb::*;
+use std::time::{Duration, Instant};
+use xcb::xproto;
+use xcb_util::ffi::icccm::XCB_ICCCM_NUM_WM_SIZE_HINTS_ELEMENTS;
+
+const ITERATIONS: u32 = 200;
+
+fn average<F: FnMut()>(mut f: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn get_property<'a>(connections: &'a XCBConnections, window: xproto::Window, atom: xproto::Atom,
+                    type_: xproto::Atom, len: u32) -> xproto::GetPropertyCookie<'a> {
+    xproto::get_property(connections.base(), false, window, atom, type_, 0, len)
+}
+
+// _NET_FRAME_EXTENTS and _GTK_FRAME_EXTENTS, the requests behind the old get_decorations
+fn request_decorations(connections: &XCBConnections, window: xproto::Window) -> [xproto::GetPropertyCookie<'_>; 2] {
+    let gtk_frame_extents = connections.atom("_GTK_FRAME_EXTENTS").unwrap();
+    [
+        get_property(connections, window, connections.ewmh.FRAME_EXTENTS(), xproto::ATOM_CARDINAL, 4),
+        get_property(connections, window, gtk_frame_extents, xproto::ATOM_CARDINAL, 4),
+    ]
+}
+
+fn wait(cookies: [xproto::GetPropertyCookie; 2]) {
+    for cookie in cookies {
+        cookie.get_reply().unwrap();
+    }
+}
+
+// The old fit_size_hints, called for both split rectangles and again by apply_split
+fn fit_size_hints(connections: &XCBConnections, window: xproto::Window) {
+    let decorations = request_decorations(connections, window);
+    get_property(connections, window, xproto::ATOM_WM_NORMAL_HINTS, xproto::ATOM_WM_SIZE_HINTS,
+                 XCB_ICCCM_NUM_WM_SIZE_HINTS_ELEMENTS)
+        .get_reply().unwrap();
+    wait(decorations);
+}
+
+fn main() {
+    let connections = match get_display(None).and_then(|display| setup_connections(&display)) {
+        Ok(connections) => connections,
+        Err(e) => {
+            eprintln!("skipping, no X server: {}", e);
+            return;
+        },
+    };
+    let base = connections.base();
+    let ewmh = &connections.ewmh;
+    let (_, screen) = get_active_window(base, ewmh).unwrap();
+    get_work_area(&connections, screen).unwrap();
+    ewmh_supports(&connections, screen, ewmh.MOVERESIZE_WINDOW()).unwrap();
+
+    let serial = average(|| {
+        let (window, screen) = get_active_window(base, ewmh).unwrap();
+        // get_window_type, WM_TRANSIENT_FOR was only asked for untyped windows
+        let types = get_property(&connections, window, ewmh.WM_WINDOW_TYPE(), xproto::ATOM_ATOM, 32)
+            .get_reply().unwrap();
+        if types.value_len() == 0 {
+            get_property(&connections, window, xproto::ATOM_WM_TRANSIENT_FOR, xproto::ATOM_WINDOW, 1)
+                .get_reply().unwrap();
+        }
+        // get_geometry
+        let decorations = request_decorations(&connections, window);
+        let geometry = xproto::get_geometry(base, window).get_reply().unwrap();
+        xproto::translate_coordinates(base, window, geometry.root(), 0, 0).get_reply().unwrap();
+        wait(decorations);
+        get_work_area(&connections, screen).unwrap();
+        // has_wm_state
+        get_property(&connections, window, ewmh.WM_STATE(), xproto::ATOM_ATOM, 32).get_reply().unwrap();
+        // fit_split for both split rectangles, then apply_split
+        fit_size_hints(&connections, window);
+        fit_size_hints(&connections, window);
+        get_work_area(&connections, screen).unwrap();
+        fit_size_hints(&connections, window);
+        // move_resize
+        let decorations = request_decorations(&connections, window);
+        ewmh_supports(&connections, screen, ewmh.MOVERESIZE_WINDOW()).unwrap();
+        wait(decorations);
+    });
+    let pipelined = average(|| {
+        let (window, screen) = get_active_window(base, ewmh).unwrap();
+        get_window_info(&connections, window, screen).unwrap();
+        get_work_area(&connections, screen).unwrap();
+        ewmh_supports(&connections, screen, ewmh.MOVERESIZE_WINDOW()).unwrap();
+    });
+
+    println!("serial lookups:  {:?} per hotkey", serial);
+    println!("one round trip:  {:?} per hotkey", pipelined);
+}
@@ -1,41 +1,305 @@
-use stateful_split_screen::errors::GenericError;
+use stateful_split_screen::errors::Error;
 use stateful_split_screen::socket::*;
 use stateful_split_screen::commands::*;
 use stateful_split_screen::data::*;
+use stateful_split_screen::config::*;
+use stateful_split_screen::signal::SignalFd;
+use std::env;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
 use std::os::unix::net::UnixDatagram;
-use clap::{AppSettings, App, SubCommand};
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::thread;
+use std::time::Duration;
+use clap::{AppSettings, App, Arg, SubCommand};
 
-fn main() -> Result<(), GenericError> {
+const REPLY_TIMEOUT_MS: u64 = 2000;
+const SERVER_BINARY: &str = "stateful-split-screen";
+const START_POLL_ATTEMPTS: u32 = 50;
+const START_POLL_MS: u64 = 100;
+
+fn check_reply(reply: &Response) -> Result<(), Error> {
+    match reply.ok {
+        true => Ok(()),
+        false => Err(Error::Remote {
+            kind: reply.error_kind.clone(),
+            details: reply.error.clone(),
+        }),
+    }
+}
+
+// Window ids are accepted the way xwininfo and xdotool print them, hex or decimal
+fn parse_window(id: &str) -> Result<u32, Error> {
+    let parsed = match id.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => id.parse(),
+    };
+    match parsed {
+        Ok(window) if window != 0 => Ok(window),
+        _ => Err(Error::invalid_command(&format!("invalid window id {}", id))),
+    }
+}
+
+fn format_uptime(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    match days {
+        0 => format!("{:02}:{:02}:{:02}", hours, minutes, seconds % 60),
+        _ => format!("{}d {:02}:{:02}:{:02}", days, hours, minutes, seconds % 60),
+    }
+}
+
+fn ping(config: &Config, display: &str) -> bool {
+    request(config, display, &Request::new(Command::Ping), Duration::from_millis(START_POLL_MS)).is_ok()
+}
+
+// Prefers the server installed next to this binary, otherwise it's looked up in PATH
+fn get_server_binary() -> PathBuf {
+    if let Ok(mut exe) = env::current_exe() {
+        exe.set_file_name(SERVER_BINARY);
+        if exe.exists() {
+            return exe;
+        }
+    }
+    PathBuf::from(SERVER_BINARY)
+}
+
+// Spawns the server in its own session so it outlives the terminal, then waits for it to answer
+fn start_server(config: &Config, config_path: Option<&str>, display: &str) -> Result<(), Error> {
+    let log_path = match &config.log.file {
+        Some(path) => path.clone(),
+        None => get_default_log_file(config, display)?,
+    };
+    // The server detaches itself and logs to the file, problems before that still reach our stderr
+    let mut command = process::Command::new(get_server_binary());
+    command.arg("--daemon").arg("--display").arg(display);
+    if let Some(path) = config_path {
+        command.arg("--config").arg(path);
+    }
+    command.stdin(Stdio::null()).stdout(Stdio::null());
+    let mut child = match command.spawn() {
+        Ok(res) => res,
+        Err(e) => return Err(Error::io("start server", e)),
+    };
+    for _ in 0..START_POLL_ATTEMPTS {
+        if ping(config, display) {
+            return Ok(());
+        }
+        // The process we started exits as soon as the daemon is forked off
+        let _ = child.try_wait();
+        thread::sleep(Duration::from_millis(START_POLL_MS));
+    }
+    Err(Error::config(&format!("server didn't start, see {}", log_path.display())))
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+// One JSON object per line, so status bars and scripts can read events without a parser for GVariant
+fn event_json(event: &Event) -> String {
+    let kind = json_string(event.kind.name());
+    match event.kind {
+        EventKind::WindowState | EventKind::ActiveWindow => format!(
+            "{{\"event\":{},\"window\":{},\"state\":{}}}", kind, event.window, json_string(&event.state)),
+        EventKind::WorkArea => format!("{{\"event\":{}}}", kind),
+        EventKind::Error => format!(
+            "{{\"event\":{},\"kind\":{},\"error\":{}}}", kind, json_string(&event.error_kind), json_string(&event.error)),
+    }
+}
+
+fn subscribe(config: &Config, display: &str) -> Result<(), Error> {
+    // Blocking the signals first lets us clean up the client socket when interrupted
+    let signals = SignalFd::new(&[libc::SIGINT, libc::SIGTERM])?;
+    let server_path = get_socket_file(config, display)?;
+    let socket = bind_client_socket(config, display)?;
+    let result = print_events(config, &socket, &server_path, &signals);
+    remove_client_socket_file(config, display)?;
+    result
+}
+
+// Prints events until we're interrupted or nobody reads our output anymore
+fn print_events(config: &Config, socket: &UnixDatagram, server_path: &Path, signals: &SignalFd) -> Result<(), Error> {
+    let reply = send_and_receive(socket, server_path, &Request::new(Command::Subscribe), config.buffer_len,
+                                 Duration::from_millis(REPLY_TIMEOUT_MS))?;
+    check_reply(&reply)?;
+    let stdout = io::stdout();
+    loop {
+        let mut fds = [
+            libc::pollfd{fd: socket.as_raw_fd(), events: libc::POLLIN, revents: 0},
+            libc::pollfd{fd: signals.fd(), events: libc::POLLIN, revents: 0},
+        ];
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(Error::last_os_error("poll"));
+        }
+        if fds[1].revents != 0 {
+            return Ok(());
+        }
+        let mut buf = vec![0; peek_datagram_size(socket, "receive event")?];
+        let size = match socket.recv(&mut buf) {
+            Ok(sz) => sz,
+            Err(e) => return Err(Error::io("receive event", e)),
+        };
+        let event = decode_event(&buf[0..size])?;
+        let mut out = stdout.lock();
+        if writeln!(out, "{}", event_json(&event)).and_then(|_| out.flush()).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+fn ratio_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(RATIO)
+        .long(RATIO)
+        .takes_value(true)
+        .help("Use this entry of the configured ratios, counting from 0, instead of cycling through them")
+}
+
+fn run() -> Result<(), Error> {
     let matches = App::new("Stateful Split Screen Client")
         .setting(AppSettings::ArgRequiredElseHelp)
+        .arg(Arg::with_name("config")
+             .long("config")
+             .takes_value(true)
+             .help("Config file to use instead of ~/.config/sss/config.toml"))
+        .arg(Arg::with_name("display")
+             .long("display")
+             .takes_value(true)
+             .help("Talk to the server of this X display instead of $DISPLAY"))
+        .arg(Arg::with_name("start")
+             .long("start")
+             .help("Start the server if it isn't running"))
+        .arg(Arg::with_name("window")
+             .long("window")
+             .takes_value(true)
+             .help("Act on this window id instead of the active window"))
+        .arg(Arg::with_name("monitor")
+             .long("monitor")
+             .takes_value(true)
+             .help("Split on this monitor, counting from 0 in the order of xrandr --listmonitors"))
         .subcommand(SubCommand::with_name(RESTORE)
                     .help("Restores window to original dimensions"))
         .subcommand(SubCommand::with_name(SPLITLEFT)
-                    .help("Resize the window to cover the left half of the desktop"))
+                    .help("Resize the window to cover the left half of the desktop")
+                    .arg(ratio_arg()))
         .subcommand(SubCommand::with_name(SPLITRIGHT)
-                    .help("Resize the window to cover the right half of the desktop"))
+                    .help("Resize the window to cover the right half of the desktop")
+                    .arg(ratio_arg()))
         .subcommand(SubCommand::with_name(MAXIMIZE)
                     .help("Maximize the window"))
+        .subcommand(SubCommand::with_name(FULLSCREEN)
+                    .help("Toggle fullscreen, leaving it restores the previous state"))
+        .subcommand(SubCommand::with_name(MINIMIZE)
+                    .help("Minimize the window"))
+        .subcommand(SubCommand::with_name(UNMINIMIZE)
+                    .help("Bring back the most recently minimized window in its previous state"))
+        .subcommand(SubCommand::with_name(DECORATE)
+                    .help("Ask the window manager to draw the window's decorations"))
+        .subcommand(SubCommand::with_name(UNDECORATE)
+                    .help("Ask the window manager to hide the window's decorations"))
+        .subcommand(SubCommand::with_name(TOGGLE_DECORATIONS)
+                    .help("Toggle the window's decorations"))
+        .subcommand(SubCommand::with_name(STATE)
+                    .help("Change a _NET_WM_STATE of the window (above, below, sticky, shaded, skip_taskbar, ...)")
+                    .arg(Arg::with_name(ACTION)
+                         .required(true)
+                         .possible_values(&[ADD, REMOVE, TOGGLE]))
+                    .arg(Arg::with_name(NAME)
+                         .required(true)
+                         .help("State name without the _NET_WM_STATE_ prefix, or a full atom name")))
         .subcommand(SubCommand::with_name(SAVE)
-                    .help("Save the current window dimensions"))
+                    .help("Save the current window dimensions")
+                    .arg(Arg::with_name(GEOMETRY)
+                         .long(GEOMETRY)
+                         .takes_value(true)
+                         .help("Save these dimensions instead, as WIDTHxHEIGHT+X+Y")))
+        .subcommand(SubCommand::with_name(RELOAD)
+                    .help("Reload the server's config file"))
+        .subcommand(SubCommand::with_name(PING)
+                    .help("Check whether the server is running"))
+        .subcommand(SubCommand::with_name(PID)
+                    .help("Print the server's process id"))
+        .subcommand(SubCommand::with_name(UPTIME)
+                    .help("Print how long the server has been running"))
         .subcommand(SubCommand::with_name(RESTART)
                     .help("Restart the server"))
         .subcommand(SubCommand::with_name(QUIT)
                     .help("Shutdown the server"))
+        .subcommand(SubCommand::with_name(SUBSCRIBE)
+                    .help("Print the server's events as JSON lines until interrupted"))
         .get_matches();
-    let commands_strings = [RESTORE, SPLITLEFT, SPLITRIGHT, MAXIMIZE, SAVE, RESTART, QUIT];
-    let command = commands_strings.iter().find(|cmd| matches.subcommand_matches(cmd).is_some()).unwrap();
-    let socket = match UnixDatagram::unbound() {
-        Ok(sock) => sock,
-        Err(_) => return Err(GenericError::new("unbound socket creation")),
+    let command = *Command::ALL.iter().find(|cmd| matches.subcommand_matches(cmd.name()).is_some()).unwrap();
+    let config_path = match matches.value_of("config") {
+        Some(path) => PathBuf::from(path),
+        None => get_config_file()?,
+    };
+    // The client only needs the socket settings, so a broken config shouldn't lock it out
+    let config = match Config::load(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}, using defaults", e);
+            Config::default()
+        },
     };
-    let server_path = get_socket_file()?;
-    let mut message = Message::new();
-    message.insert(COMMAND, command);
-    let message_enc = encode_data(message)?;
-    if let Err(_) = socket.send_to(&message_enc, server_path.as_path()) {
-        return Err(GenericError::new("send message to socket"));
+    let display = get_display(matches.value_of("display"))?;
+    if matches.is_present("start") && !ping(&config, &display) {
+        start_server(&config, matches.value_of("config"), &display)?;
+    }
+    if command == Command::Subscribe {
+        return subscribe(&config, &display);
+    }
+    let mut message = Request::new(command);
+    if let Some(window) = matches.value_of("window") {
+        message.target.window = parse_window(window)?;
+    }
+    if let Some(monitor) = matches.value_of("monitor") {
+        message.target.monitor = match monitor.parse() {
+            Ok(monitor) if monitor >= 0 => monitor,
+            _ => return Err(Error::invalid_command(&format!("invalid monitor {}", monitor))),
+        };
+    }
+    let command_matches = matches.subcommand_matches(command.name()).unwrap();
+    if command == Command::State {
+        // clap already restricted the action to the possible values
+        message.arguments.state_action = StateAction::from_name(command_matches.value_of(ACTION).unwrap()).unwrap();
+        message.arguments.state_name = command_matches.value_of(NAME).unwrap().to_string();
+    }
+    if let Some(ratio) = command_matches.value_of(RATIO) {
+        message.arguments.ratio = match ratio.parse() {
+            Ok(ratio) if ratio >= 0 => ratio,
+            _ => return Err(Error::invalid_command(&format!("invalid ratio index {}", ratio))),
+        };
+    }
+    if let Some(geometry) = command_matches.value_of(GEOMETRY) {
+        message.arguments.geometry = parse_geometry(geometry)?;
+    }
+    let reply = request(&config, &display, &message, Duration::from_millis(REPLY_TIMEOUT_MS))?;
+    check_reply(&reply)?;
+    match command {
+        Command::Pid => println!("{}", reply.pid),
+        Command::Uptime => println!("{}", format_uptime(reply.uptime)),
+        _ => (),
     }
-
     Ok(())
 }
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
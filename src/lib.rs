@@ -1,292 +1,1957 @@
 pub mod errors {
-    use std::error::Error;
+    use std::error;
     use std::fmt;
+    use std::io;
 
     #[derive(Debug)]
-    pub struct GenericError {
-        details: String,
+    pub enum Error {
+        /// The X server rejected a request, usually because the window went away
+        X11 {
+            context: String,
+            error_code: u8,
+            major_opcode: u8,
+            minor_opcode: u16,
+            resource_id: u32,
+        },
+        /// The connection to the X server is broken, nothing works until we reconnect
+        ConnectionLost {
+            context: String,
+            source: Option<xcb::ConnError>,
+        },
+        /// A message couldn't be encoded or decoded
+        Encoding {
+            context: String,
+            source: zvariant::Error,
+        },
+        /// Reading or writing sockets and files failed
+        Io {
+            context: String,
+            source: io::Error,
+        },
+        /// No server answers on the socket
+        ServerNotRunning,
+        /// The request doesn't make sense, like an unknown command or state
+        InvalidCommand(String),
+        /// The window or desktop isn't in a shape we can work with
+        Window(String),
+        /// A property xcb-util was asked for isn't set or has another type, with what asked
+        PropertyMissing(String),
+        /// The config file or the environment is wrong
+        Config(String),
+        /// The other side speaks another version of the socket protocol
+        Protocol(String),
+        /// An error the server sent back, with the kind it had over there
+        Remote {
+            kind: String,
+            details: String,
+        },
     }
 
-    impl GenericError {
-        pub fn new(details: &str) -> GenericError {
-            GenericError{details: details.to_string()}
+    impl Error {
+        /// Failed xcb requests without an error are either a lost connection or, for xcb-util's
+        /// property getters, a property that is missing or has another type
+        pub fn x11(base: &xcb::base::Connection, context: &str, e: xcb::GenericError) -> Error {
+            if e.ptr.is_null() {
+                return match base.has_error() {
+                    Err(source) => Error::ConnectionLost{context: context.to_string(), source: Some(source)},
+                    Ok(_) => Error::PropertyMissing(context.to_string()),
+                };
+            }
+            let generic = unsafe { *e.ptr };
+            Error::X11 {
+                context: context.to_string(),
+                error_code: generic.error_code,
+                major_opcode: generic.major_code,
+                minor_opcode: generic.minor_code,
+                resource_id: generic.resource_id,
+            }
+        }
+
+        pub fn connection(context: &str, source: Option<xcb::ConnError>) -> Error {
+            Error::ConnectionLost{context: context.to_string(), source}
+        }
+
+        pub fn encoding(context: &str, source: zvariant::Error) -> Error {
+            Error::Encoding{context: context.to_string(), source}
+        }
+
+        pub fn io(context: &str, source: io::Error) -> Error {
+            Error::Io{context: context.to_string(), source}
+        }
+
+        /// For failed libc calls, which leave their reason in errno
+        pub fn last_os_error(context: &str) -> Error {
+            Error::io(context, io::Error::last_os_error())
+        }
+
+        pub fn invalid_command(details: &str) -> Error {
+            Error::InvalidCommand(details.to_string())
+        }
+
+        pub fn window(details: &str) -> Error {
+            Error::Window(details.to_string())
+        }
+
+        pub fn config(details: &str) -> Error {
+            Error::Config(details.to_string())
+        }
+
+        pub fn protocol(details: &str) -> Error {
+            Error::Protocol(details.to_string())
+        }
+
+        /// Short name sent to clients along with the message
+        pub fn kind(&self) -> &str {
+            match self {
+                Error::X11{..} => "x11",
+                Error::ConnectionLost{..} => "connection-lost",
+                Error::Encoding{..} => "encoding",
+                Error::Io{..} => "io",
+                Error::ServerNotRunning => "server-not-running",
+                Error::InvalidCommand(_) => "invalid-command",
+                Error::Window(_) => "window",
+                Error::PropertyMissing(_) => "property-missing",
+                Error::Config(_) => "config",
+                Error::Protocol(_) => "protocol",
+                Error::Remote{kind, ..} => kind,
+            }
+        }
+
+        /// Whether the server has to give up its X connection, everything else only fails one command
+        pub fn is_fatal(&self) -> bool {
+            matches!(self, Error::ConnectionLost{..})
         }
     }
 
-    impl Error for GenericError {
+    impl error::Error for Error {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            match self {
+                Error::ConnectionLost{source: Some(source), ..} => Some(source),
+                Error::Encoding{source, ..} => Some(source),
+                Error::Io{source, ..} => Some(source),
+                _ => None,
+            }
+        }
     }
 
-    impl fmt::Display for GenericError {
+    impl fmt::Display for Error {
         fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            write!(f, "ERROR: {}", self.details)
+            match self {
+                Error::X11{context, error_code, major_opcode, minor_opcode, resource_id} =>
+                    write!(f, "{}: X error {} (request {}.{}, resource {:#x})",
+                           context, error_code, major_opcode, minor_opcode, resource_id),
+                Error::ConnectionLost{context, ..} => write!(f, "{}: lost connection to the X server", context),
+                Error::Encoding{context, source} => write!(f, "{}: {}", context, source),
+                Error::Io{context, source} => write!(f, "{}: {}", context, source),
+                Error::ServerNotRunning => write!(f, "server is not running"),
+                Error::InvalidCommand(details) | Error::Window(details) | Error::Config(details) | Error::Protocol(details) =>
+                    write!(f, "{}", details),
+                Error::PropertyMissing(context) => write!(f, "{}: property missing", context),
+                Error::Remote{details, ..} => write!(f, "{}", details),
+            }
         }
     }
 }
 
 pub mod commands {
-    pub const COMMAND: &str = "command";
+    use serde::{Deserialize, Serialize};
+    use zvariant_derive::Type;
+
     pub const RESTORE: &str = "restore";
     pub const SPLITLEFT: &str = "splitleft";
     pub const SPLITRIGHT: &str = "splitright";
     pub const MAXIMIZE: &str = "maximize";
+    pub const FULLSCREEN: &str = "fullscreen";
+    pub const STATE: &str = "state";
+    pub const MINIMIZE: &str = "minimize";
+    pub const UNMINIMIZE: &str = "unminimize";
+    pub const DECORATE: &str = "decorate";
+    pub const UNDECORATE: &str = "undecorate";
+    pub const TOGGLE_DECORATIONS: &str = "toggle-decorations";
     pub const RESTART: &str = "restart";
+    pub const RELOAD: &str = "reload";
+    pub const PING: &str = "ping";
+    pub const PID: &str = "pid";
+    pub const UPTIME: &str = "uptime";
     pub const SAVE: &str = "save";
     pub const QUIT: &str = "quit";
+    pub const SUBSCRIBE: &str = "subscribe";
+
+    // Arguments of the state command
+    pub const ACTION: &str = "action";
+    pub const NAME: &str = "name";
+    pub const ADD: &str = "add";
+    pub const REMOVE: &str = "remove";
+    pub const TOGGLE: &str = "toggle";
+
+    // Arguments of the split and save commands
+    pub const RATIO: &str = "ratio";
+    pub const GEOMETRY: &str = "geometry";
+
+    /// The commands above as they're sent over the socket. Variants are encoded by their index,
+    /// so changing the order needs a new protocol version.
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Type)]
+    pub enum Command {
+        Restore,
+        SplitLeft,
+        SplitRight,
+        Maximize,
+        Fullscreen,
+        State,
+        Minimize,
+        Unminimize,
+        Decorate,
+        Undecorate,
+        ToggleDecorations,
+        Restart,
+        Reload,
+        Ping,
+        Pid,
+        Uptime,
+        Save,
+        Quit,
+        Subscribe,
+    }
+
+    impl Command {
+        pub const ALL: [Command; 19] = [
+            Command::Restore, Command::SplitLeft, Command::SplitRight, Command::Maximize, Command::Fullscreen,
+            Command::State, Command::Minimize, Command::Unminimize, Command::Decorate, Command::Undecorate,
+            Command::ToggleDecorations, Command::Restart, Command::Reload, Command::Ping, Command::Pid,
+            Command::Uptime, Command::Save, Command::Quit, Command::Subscribe,
+        ];
+
+        /// The name used on the command line and in the config file
+        pub fn name(&self) -> &'static str {
+            match self {
+                Command::Restore => RESTORE,
+                Command::SplitLeft => SPLITLEFT,
+                Command::SplitRight => SPLITRIGHT,
+                Command::Maximize => MAXIMIZE,
+                Command::Fullscreen => FULLSCREEN,
+                Command::State => STATE,
+                Command::Minimize => MINIMIZE,
+                Command::Unminimize => UNMINIMIZE,
+                Command::Decorate => DECORATE,
+                Command::Undecorate => UNDECORATE,
+                Command::ToggleDecorations => TOGGLE_DECORATIONS,
+                Command::Restart => RESTART,
+                Command::Reload => RELOAD,
+                Command::Ping => PING,
+                Command::Pid => PID,
+                Command::Uptime => UPTIME,
+                Command::Save => SAVE,
+                Command::Quit => QUIT,
+                Command::Subscribe => SUBSCRIBE,
+            }
+        }
+
+        pub fn from_name(name: &str) -> Option<Command> {
+            Command::ALL.iter().find(|command| command.name() == name).copied()
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Type)]
+    pub enum StateAction {
+        Add,
+        Remove,
+        Toggle,
+    }
+
+    impl StateAction {
+        pub fn from_name(name: &str) -> Option<StateAction> {
+            match name {
+                ADD => Some(StateAction::Add),
+                REMOVE => Some(StateAction::Remove),
+                TOGGLE => Some(StateAction::Toggle),
+                _ => None,
+            }
+        }
+    }
 }
 
 pub mod data {
+    use std::cell::Cell;
     use std::collections::HashMap;
+    use std::panic;
+    use std::sync::Once;
     use byteorder::LE;
-    use zvariant::{from_slice, to_bytes};
+    use serde::{Deserialize, Serialize};
+    use serde::de::DeserializeOwned;
+    use zvariant::{from_slice, to_bytes, Type};
     use zvariant::EncodingContext as Context;
-    use crate::errors::GenericError;
+    use zvariant_derive::Type;
+    use crate::commands::{Command, StateAction};
+    use crate::errors::Error;
+    use crate::xcb::Rect;
+
+    /// Bumped whenever Request or Response change shape
+    pub const PROTOCOL_VERSION: u32 = 2;
+
+    /// Arguments::ratio for splits that cycle through the configured ratios when repeated
+    pub const CYCLE_RATIO: i32 = -1;
+
+    /// What a client asks the server to do
+    #[derive(Clone, Debug, Serialize, Deserialize, Type)]
+    pub struct Request {
+        pub command: Command,
+        pub target: Target,
+        pub arguments: Arguments,
+    }
+
+    impl Request {
+        pub fn new(command: Command) -> Request {
+            Request{command, target: Target::default(), arguments: Arguments::default()}
+        }
+    }
+
+    /// Target::monitor for splits that cover the whole work area, or stay on the monitor the
+    /// window was last split on
+    pub const ALL_MONITORS: i32 = -1;
+
+    /// What a command acts on
+    #[derive(Clone, Debug, Serialize, Deserialize, Type)]
+    pub struct Target {
+        /// 0 for the active window
+        pub window: u32,
+        /// Splits: the monitor whose part of the work area is split, or ALL_MONITORS
+        pub monitor: i32,
+    }
+
+    impl Default for Target {
+        fn default() -> Target {
+            Target{window: 0, monitor: ALL_MONITORS}
+        }
+    }
+
+    /// Each command only reads its own arguments and ignores the rest
+    #[derive(Clone, Debug, Serialize, Deserialize, Type)]
+    pub struct Arguments {
+        /// Splits: index into the configured ratios, or CYCLE_RATIO
+        pub ratio: i32,
+        /// Command::Save: the geometry to restore the window to, an empty one saves the
+        /// window's current geometry
+        pub geometry: Rect,
+        /// Command::State: what to do with which _NET_WM_STATE
+        pub state_action: StateAction,
+        pub state_name: String,
+    }
+
+    impl Default for Arguments {
+        fn default() -> Arguments {
+            Arguments{
+                ratio: CYCLE_RATIO,
+                geometry: Rect::default(),
+                state_action: StateAction::Toggle,
+                state_name: String::new(),
+            }
+        }
+    }
 
-    pub struct Message {
-        map: HashMap<String, String>,
+    /// The server's answer, every request gets exactly one
+    #[derive(Clone, Debug, Default, Serialize, Deserialize, Type)]
+    pub struct Response {
+        pub ok: bool,
+        /// Error::kind and the message when the command failed
+        pub error_kind: String,
+        pub error: String,
+        /// Only filled in by Command::Pid and Command::Uptime
+        pub pid: u32,
+        pub uptime: u64,
     }
 
-    impl Message {
-        pub fn new() -> Message {
-            let map = HashMap::new();
-            Message{map}
+    impl Response {
+        pub fn new(result: &Result<(), Error>) -> Response {
+            match result {
+                Ok(_) => Response{ok: true, ..Response::default()},
+                Err(e) => Response{
+                    ok: false,
+                    error_kind: e.kind().to_string(),
+                    error: e.to_string(),
+                    ..Response::default()
+                },
+            }
         }
-        pub fn get(&self, key: &str) -> Option<&str> {
-            match self.map.get(key) {
-                Some(res) => Some(res.as_str()),
-                None => None,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Type)]
+    pub enum EventKind {
+        /// A window we manage changed state, through any command, key binding or rule
+        WindowState,
+        /// The focus moved, `window` is 0 when nothing has it
+        ActiveWindow,
+        /// The work area changed, usually because monitors or panels came or went
+        WorkArea,
+        /// A command failed
+        Error,
+    }
+
+    impl EventKind {
+        pub fn name(&self) -> &'static str {
+            match self {
+                EventKind::WindowState => "window-state",
+                EventKind::ActiveWindow => "active-window",
+                EventKind::WorkArea => "work-area",
+                EventKind::Error => "error",
             }
         }
-        pub fn insert(&mut self, key: &str, value: &str) {
-            self.map.insert(key.to_string(), value.to_string());
+    }
+
+    /// What the server sends to clients that subscribed
+    #[derive(Clone, Debug, Serialize, Deserialize, Type)]
+    pub struct Event {
+        pub kind: EventKind,
+        pub window: u32,
+        /// The window's state, like "splitleft", empty when we don't manage it
+        pub state: String,
+        /// Error::kind and the message for EventKind::Error
+        pub error_kind: String,
+        pub error: String,
+    }
+
+    impl Event {
+        pub fn new(kind: EventKind) -> Event {
+            Event{kind, window: 0, state: String::new(), error_kind: String::new(), error: String::new()}
+        }
+
+        pub fn error(error: &Error) -> Event {
+            Event{error_kind: error.kind().to_string(), error: error.to_string(), ..Event::new(EventKind::Error)}
         }
-        fn from_expose(map: HashMap<String, String>) -> Message {
-            Message{map}
+    }
+
+    /// Reads X geometry strings like xdotool and xwininfo -geometry use: WIDTHxHEIGHT+X+Y
+    pub fn parse_geometry(geometry: &str) -> Result<Rect, Error> {
+        let invalid = || Error::invalid_command(&format!("invalid geometry {}, expected WIDTHxHEIGHT+X+Y", geometry));
+        let (size, position) = match geometry.find(['+', '-']) {
+            Some(idx) => geometry.split_at(idx),
+            None => return Err(invalid()),
+        };
+        let (width, height) = match size.split_once('x') {
+            Some((width, height)) => (width.parse().map_err(|_| invalid())?, height.parse().map_err(|_| invalid())?),
+            None => return Err(invalid()),
+        };
+        // The sign belongs to the number that follows it
+        let split = match position[1..].find(['+', '-']) {
+            Some(idx) => idx + 1,
+            None => return Err(invalid()),
+        };
+        let (x, y) = position.split_at(split);
+        match (x.parse(), y.parse()) {
+            (Ok(x), Ok(y)) if width != 0 && height != 0 => Ok(Rect{x, y, width, height}),
+            _ => Err(invalid()),
         }
-        fn expose(&mut self) -> HashMap<String, String> {
-            std::mem::replace(&mut self.map, HashMap::new())
+    }
+
+    /// Turns the words of a key binding or rule, like "splitleft 1", into the request sssc
+    /// would have sent
+    pub fn binding_request(words: &[String]) -> Result<Request, Error> {
+        let (name, arguments) = match words.split_first() {
+            Some(res) => res,
+            None => return Err(Error::invalid_command("empty command")),
+        };
+        let command = match Command::from_name(name) {
+            Some(command) => command,
+            None => return Err(Error::invalid_command(&format!("unknown command {}", name))),
+        };
+        let mut request = Request::new(command);
+        let max_arguments = match command {
+            Command::State => {
+                let (action, state) = match arguments {
+                    [action, state, ..] => (action, state),
+                    _ => return Err(Error::invalid_command("state needs add, remove or toggle and a state name")),
+                };
+                request.arguments.state_action = match StateAction::from_name(action) {
+                    Some(action) => action,
+                    None => return Err(Error::invalid_command(&format!("invalid state action {}", action))),
+                };
+                request.arguments.state_name = state.to_string();
+                2
+            },
+            // "splitleft 1" always splits with the second ratio instead of cycling
+            Command::SplitLeft | Command::SplitRight => {
+                if let Some(ratio) = arguments.first() {
+                    request.arguments.ratio = match ratio.parse() {
+                        Ok(ratio) if ratio >= 0 => ratio,
+                        _ => return Err(Error::invalid_command(&format!("invalid split ratio index {}", ratio))),
+                    };
+                }
+                1
+            },
+            // "save 800x600+0+0" restores to that geometry instead of the current one
+            Command::Save => {
+                if let Some(geometry) = arguments.first() {
+                    request.arguments.geometry = parse_geometry(geometry)?;
+                }
+                1
+            },
+            _ => 0,
+        };
+        if arguments.len() > max_arguments {
+            return Err(Error::invalid_command(&format!(
+                "unexpected {} after {}", arguments[max_arguments..].join(" "), words[..=max_arguments].join(" "))));
         }
+        Ok(request)
     }
 
-    pub fn encode_data(mut message: Message) -> Result<Vec<u8>, GenericError> {
+    // Every datagram is the protocol version followed by the encoded message, so the version
+    // can still be read when the message has a shape we don't know
+    fn encode<T: Serialize + Type>(message: &T) -> Result<Vec<u8>, Error> {
         let ctxt = Context::<LE>::new_gvariant(0);
-        match to_bytes(ctxt, &message.expose()) {
+        let payload = match to_bytes(ctxt, message) {
+            Ok(res) => res,
+            Err(e) => return Err(Error::encoding("gvariant encoding", e)),
+        };
+        match to_bytes(ctxt, &(PROTOCOL_VERSION, payload)) {
             Ok(res) => Ok(res),
-            Err(_) => return Err(GenericError::new("gvariant encoding")),
+            Err(e) => Err(Error::encoding("gvariant encoding", e)),
+        }
+    }
+
+    thread_local! {
+        static DECODING: Cell<bool> = const { Cell::new(false) };
+    }
+
+    static QUIET_DECODING_PANICS: Once = Once::new();
+
+    // zvariant panics on some malformed input instead of failing, e.g. strings cut off before
+    // their nul, and anyone who can reach the socket can send us that. The panic is caught,
+    // which relies on panic = "unwind" in Cargo.toml, and kept out of the log since it's the
+    // sender's mistake, not ours.
+    fn from_slice_checked<T: DeserializeOwned + Type>(binary: &[u8]) -> Result<T, zvariant::Error> {
+        QUIET_DECODING_PANICS.call_once(|| {
+            let default_hook = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                if !DECODING.with(Cell::get) {
+                    default_hook(info);
+                }
+            }));
+        });
+        let ctxt = Context::<LE>::new_gvariant(0);
+        DECODING.with(|decoding| decoding.set(true));
+        let result = panic::catch_unwind(|| from_slice(binary, ctxt));
+        DECODING.with(|decoding| decoding.set(false));
+        match result {
+            Ok(res) => res,
+            Err(_) => Err(zvariant::Error::Message("malformed message".to_string())),
+        }
+    }
+
+    // Returns the peer's version when it isn't ours
+    fn decode<T: DeserializeOwned + Type>(binary: &[u8]) -> Result<Result<T, u32>, Error> {
+        let (version, payload): (u32, Vec<u8>) = match from_slice_checked(binary) {
+            Ok(res) => res,
+            Err(e) => return Err(Error::encoding("gvariant decoding", e)),
+        };
+        if version != PROTOCOL_VERSION {
+            return Ok(Err(version));
+        }
+        match from_slice_checked(&payload) {
+            Ok(res) => Ok(Ok(res)),
+            Err(e) => Err(Error::encoding("gvariant decoding", e)),
+        }
+    }
+
+    pub fn encode_request(request: &Request) -> Result<Vec<u8>, Error> {
+        encode(request)
+    }
+
+    pub fn encode_response(response: &Response) -> Result<Vec<u8>, Error> {
+        encode(response)
+    }
+
+    pub fn encode_event(event: &Event) -> Result<Vec<u8>, Error> {
+        encode(event)
+    }
+
+    pub fn decode_request(binary: &[u8]) -> Result<Request, Error> {
+        match decode(binary)? {
+            Ok(request) => Ok(request),
+            Err(version) => Err(Error::protocol(&format!(
+                "sssc speaks protocol version {} but the server speaks {}, use the sssc that came with the server",
+                version, PROTOCOL_VERSION))),
+        }
+    }
+
+    // Replies and events only come from the server
+    fn server_version_mismatch(version: u32) -> Error {
+        Error::protocol(&format!(
+            "the server speaks protocol version {} but sssc speaks {}, restart the server after upgrading",
+            version, PROTOCOL_VERSION))
+    }
+
+    pub fn decode_response(binary: &[u8]) -> Result<Response, Error> {
+        decode(binary)?.map_err(server_version_mismatch)
+    }
+
+    pub fn decode_event(binary: &[u8]) -> Result<Event, Error> {
+        decode(binary)?.map_err(server_version_mismatch)
+    }
+
+    // Clients from before protocol versions send and expect plain a{ss} dictionaries
+    const LEGACY_COMMAND: &str = "command";
+
+    pub fn is_legacy_request(binary: &[u8]) -> bool {
+        let message: Result<HashMap<String, String>, _> = from_slice_checked(binary);
+        match message {
+            Ok(map) => map.contains_key(LEGACY_COMMAND),
+            Err(_) => false,
         }
     }
 
-    pub fn decode_data(binary: &[u8]) -> Result<Message, GenericError> {
+    /// An error reply that old clients can show to their user
+    pub fn encode_legacy_error(error: &Error) -> Result<Vec<u8>, Error> {
+        let mut map = HashMap::new();
+        map.insert("status".to_string(), "error".to_string());
+        map.insert("error".to_string(), error.to_string());
+        map.insert("kind".to_string(), error.kind().to_string());
         let ctxt = Context::<LE>::new_gvariant(0);
-        match from_slice(&binary, ctxt) {
-            Ok(res) => Ok(Message::from_expose(res)),
-            Err(_) => return Err(GenericError::new("gvariant decoding")),
+        match to_bytes(ctxt, &map) {
+            Ok(res) => Ok(res),
+            Err(e) => Err(Error::encoding("gvariant encoding", e)),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn test_request() -> Request {
+            let mut request = Request::new(Command::State);
+            request.target.window = 0x1234;
+            request.arguments.state_name = "_NET_WM_STATE_ABOVE".to_string();
+            request
+        }
+
+        fn legacy_request() -> Vec<u8> {
+            let mut map = HashMap::new();
+            map.insert(LEGACY_COMMAND.to_string(), "splitleft".to_string());
+            to_bytes(Context::<LE>::new_gvariant(0), &map).unwrap()
+        }
+
+        fn with_version(version: u32, payload: Vec<u8>) -> Vec<u8> {
+            to_bytes(Context::<LE>::new_gvariant(0), &(version, payload)).unwrap()
+        }
+
+        // Deterministic so a failure can be reproduced
+        fn random_bytes(seed: u64, len: usize) -> Vec<u8> {
+            let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+            (0..len).map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            }).collect()
+        }
+
+        // Everything a confused or hostile peer could send us instead of a message
+        fn corpus() -> Vec<Vec<u8>> {
+            let ctxt = Context::<LE>::new_gvariant(0);
+            let request = encode_request(&test_request()).unwrap();
+            let response = encode_response(&Response::new(&Ok(()))).unwrap();
+            let mut corpus = vec![
+                Vec::new(),
+                vec![0],
+                vec![0xff; 3],
+                legacy_request(),
+                with_version(PROTOCOL_VERSION - 1, Vec::new()),
+                with_version(PROTOCOL_VERSION + 1, to_bytes(ctxt, &test_request()).unwrap()),
+                with_version(u32::MAX, vec![0xff; 64]),
+                with_version(PROTOCOL_VERSION, Vec::new()),
+                with_version(PROTOCOL_VERSION, vec![0xff; 64]),
+                with_version(PROTOCOL_VERSION, legacy_request()),
+            ];
+            for message in [&request, &response, &legacy_request()] {
+                for len in 0..message.len() {
+                    corpus.push(message[..len].to_vec());
+                }
+                // GVariant keeps framing offsets and string terminators at the end, make them
+                // point past the end of the message
+                for i in message.len() - 2..message.len() {
+                    let mut oversized = message.clone();
+                    oversized[i] = 0xff;
+                    corpus.push(oversized);
+                }
+                let mut oversized = message.clone();
+                oversized.extend_from_slice(&[0xff; 8]);
+                corpus.push(oversized);
+            }
+            for seed in 0..256 {
+                corpus.push(random_bytes(seed, (seed % 64) as usize));
+            }
+            corpus
+        }
+
+        #[test]
+        fn round_trip() {
+            let decoded = decode_request(&encode_request(&test_request()).unwrap()).unwrap();
+            assert_eq!(decoded.command, Command::State);
+            assert_eq!(decoded.target.window, 0x1234);
+            assert_eq!(decoded.arguments.state_name, "_NET_WM_STATE_ABOVE");
+            assert!(decode_response(&encode_response(&Response::new(&Ok(()))).unwrap()).unwrap().ok);
+        }
+
+        #[test]
+        fn legacy_request_detected() {
+            assert!(is_legacy_request(&legacy_request()));
+            assert!(!is_legacy_request(&encode_request(&test_request()).unwrap()));
+            assert!(decode_request(&legacy_request()).is_err());
+        }
+
+        fn words(command: &str) -> Vec<String> {
+            command.split_whitespace().map(String::from).collect()
+        }
+
+        #[test]
+        fn geometry_strings() {
+            assert_eq!(parse_geometry("800x600+10+20").unwrap(), Rect{x: 10, y: 20, width: 800, height: 600});
+            assert_eq!(parse_geometry("800x600-10-20").unwrap(), Rect{x: -10, y: -20, width: 800, height: 600});
+            for invalid in ["", "800x600", "800x600+10", "0x600+0+0", "800+0+0", "axb+0+0", "800x600+0+0+0"] {
+                assert!(parse_geometry(invalid).is_err(), "parsed {}", invalid);
+            }
+        }
+
+        #[test]
+        fn binding_arguments() {
+            let request = binding_request(&words("save 800x600+0+0")).unwrap();
+            assert_eq!(request.arguments.geometry, Rect{x: 0, y: 0, width: 800, height: 600});
+            assert_eq!(binding_request(&words("splitleft 1")).unwrap().arguments.ratio, 1);
+            assert_eq!(binding_request(&words("splitright")).unwrap().arguments.ratio, CYCLE_RATIO);
+            let request = binding_request(&words("state add above")).unwrap();
+            assert_eq!(request.arguments.state_action, StateAction::Add);
+            assert_eq!(request.arguments.state_name, "above");
+        }
+
+        #[test]
+        fn binding_extra_words_rejected() {
+            for invalid in ["", "nonsense", "maximize now", "save 800x600+0+0 again", "save later",
+                            "splitleft 1 2", "splitleft -1", "state add", "state flip above", "state add above below"] {
+                assert!(binding_request(&words(invalid)).is_err(), "accepted {}", invalid);
+            }
+        }
+
+        #[test]
+        fn wrong_version_rejected() {
+            let payload = to_bytes(Context::<LE>::new_gvariant(0), &test_request()).unwrap();
+            match decode_request(&with_version(PROTOCOL_VERSION + 1, payload)) {
+                Err(Error::Protocol(_)) => (),
+                other => panic!("expected a protocol error, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn malformed_input_rejected() {
+            let request = encode_request(&test_request()).unwrap();
+            let response = encode_response(&Response::new(&Ok(()))).unwrap();
+            let legacy = legacy_request();
+            for binary in corpus() {
+                if binary != request {
+                    assert!(decode_request(&binary).is_err(), "decoded a request from {:02x?}", binary);
+                }
+                if binary != response {
+                    assert!(decode_response(&binary).is_err(), "decoded a response from {:02x?}", binary);
+                }
+                if binary != legacy {
+                    assert!(!is_legacy_request(&binary), "legacy request in {:02x?}", binary);
+                }
+            }
         }
     }
 }
 
 pub mod socket {
-    use crate::errors::GenericError;
+    use crate::errors::Error;
+    use crate::config::Config;
+    use crate::data::*;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+    use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
     use std::os::unix::net::UnixDatagram;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::env;
     use std::fs;
+    use std::mem;
+    use std::ptr;
+    use std::time::Duration;
+
+    pub const SOCKET_BUFFER_LEN: usize = 1024;
+    const PRIVATE_DIR_MODE: u32 = 0o700;
+    const LISTEN_FDS_START: RawFd = 3;
 
-    fn get_socket_dir() -> Result<PathBuf, GenericError> {
-        if let Some(mut cachepath) = dirs::cache_dir() {
-            cachepath.push("sss_socket");
-            Ok(cachepath)
+    /// The X display from the command line, or $DISPLAY
+    pub fn get_display(display: Option<&str>) -> Result<String, Error> {
+        if let Some(display) = display {
+            return Ok(display.to_string());
+        }
+        match env::var("DISPLAY") {
+            Ok(display) if !display.is_empty() => Ok(display),
+            _ => Err(Error::config("DISPLAY is not set, pass --display")),
+        }
+    }
+
+    // Screens of one display share a server, so only the host and display number go in the name
+    fn get_socket_name(display: &str) -> Result<String, Error> {
+        let (host, number) = match display.rfind(':') {
+            Some(idx) => (&display[..idx], display[idx + 1..].split('.').next().unwrap_or("")),
+            None => return Err(Error::config("invalid display name")),
+        };
+        if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+            return Err(Error::config("invalid display number"));
+        }
+        match host {
+            "" | "unix" => Ok(format!("sss_socket_{}", number)),
+            _ => Ok(format!("sss_socket_{}_{}", host.replace('/', "_"), number)),
+        }
+    }
+
+    // $XDG_RUNTIME_DIR is already private to the user, without it we make our own directory
+    fn get_runtime_dir() -> Result<PathBuf, Error> {
+        if let Some(runtimepath) = dirs::runtime_dir() {
+            return Ok(runtimepath);
+        }
+        let privatepath = if let Some(mut cachepath) = dirs::cache_dir() {
+            cachepath.push("sss");
+            cachepath
         }
         else if let Some(mut homepath) = dirs::home_dir() {
-            homepath.push(".sss_socket");
-            Ok(homepath)
+            homepath.push(".sss");
+            homepath
         }
         else {
-            Err(GenericError::new("getting cache or home directory"))
+            return Err(Error::config("getting cache or home directory"));
+        };
+        if let Err(e) = fs::DirBuilder::new().recursive(true).mode(PRIVATE_DIR_MODE).create(&privatepath) {
+            return Err(Error::io("create socket directory", e));
+        }
+        let metadata = match fs::metadata(&privatepath) {
+            Ok(res) => res,
+            Err(e) => return Err(Error::io("stat socket directory", e)),
+        };
+        if metadata.uid() != unsafe { libc::getuid() } {
+            return Err(Error::config("socket directory is owned by another user"));
+        }
+        // The directory may predate us, or have been created with a looser umask
+        if metadata.mode() & 0o777 != PRIVATE_DIR_MODE {
+            if let Err(e) = fs::set_permissions(&privatepath, fs::Permissions::from_mode(PRIVATE_DIR_MODE)) {
+                return Err(Error::io("restrict socket directory permissions", e));
+            }
         }
+        Ok(privatepath)
     }
 
-    pub fn get_socket_file() -> Result<PathBuf, GenericError> {
-        let socket_path = get_socket_dir()?;
+    fn get_socket_dir(config: &Config, display: &str) -> Result<PathBuf, Error> {
+        if let Some(socket_path) = &config.socket_path {
+            return Ok(socket_path.clone());
+        }
+        let mut socket_path = get_runtime_dir()?;
+        socket_path.push(get_socket_name(display)?);
+        Ok(socket_path)
+    }
+
+    // The umask is set around bind so the socket never exists with looser permissions
+    fn bind_private(socket_path: &Path) -> Result<UnixDatagram, Error> {
+        let old_umask = unsafe { libc::umask(0o177) };
+        let socket = UnixDatagram::bind(socket_path);
+        unsafe { libc::umask(old_umask) };
+        match socket {
+            Ok(sock) => Ok(sock),
+            Err(e) => Err(Error::io("socket binding", e)),
+        }
+    }
+
+    pub fn get_socket_file(config: &Config, display: &str) -> Result<PathBuf, Error> {
+        let socket_path = get_socket_dir(config, display)?;
         match socket_path.exists() {
             true => Ok(socket_path),
-            _ => Err(GenericError::new("socket does not exists")),
+            _ => Err(Error::ServerNotRunning),
         }
     }
 
-    pub fn remove_socket_file() -> Result<(), GenericError> {
-        let socket_path = get_socket_dir()?;
+    pub fn remove_socket_file(config: &Config, display: &str) -> Result<(), Error> {
+        let socket_path = get_socket_dir(config, display)?;
         if socket_path.exists() {
-            if let Err(_) = fs::remove_file(socket_path.as_path()) {
-                return Err(GenericError::new("cannot remove old socket"));
+            if let Err(e) = fs::remove_file(socket_path.as_path()) {
+                return Err(Error::io("cannot remove old socket", e));
             }
         }
         Ok(())
     }
 
-    // TODO modify permissions, race condition potential btw
-    pub fn bind_socket() -> Result<UnixDatagram, GenericError> {
-        let socket_path = get_socket_dir()?;
-        remove_socket_file()?;
-        match UnixDatagram::bind(socket_path.as_path()) {
-            Ok(sock) => Ok(sock),
-            Err(_) => Err(GenericError::new("socket binding")),
-        }
+    // Files that belong to one server live next to its socket
+    fn get_sibling_file(config: &Config, display: &str, extension: &str) -> Result<PathBuf, Error> {
+        let mut socket_path = get_socket_dir(config, display)?.into_os_string();
+        socket_path.push(format!(".{}", extension));
+        Ok(PathBuf::from(socket_path))
     }
-}
 
-pub mod xcb {
-    use crate::errors::GenericError;
-    use xcb_util::ewmh;
-    use xcb::base;
-    use xcb::xproto;
-    
-    pub struct XCBConnections {
-        pub base: base::Connection,
-        pub ewmh: ewmh::Connection,
-        pub screen: i32,
+    // The client binds next to the server socket so the server has an address to reply to
+    pub fn get_client_socket_file(config: &Config, display: &str) -> Result<PathBuf, Error> {
+        get_sibling_file(config, display, &process::id().to_string())
     }
-    
-    pub fn get_screen_root_window(base: &base::Connection, screen: i32) -> Result<xproto::Window, GenericError> {
-        let setup = base.get_setup();
-        match setup.roots().nth(screen as usize) {
-            Some(screen) => Ok(screen.root()),
-            None => Err(GenericError::new("iterating through screens")),
+
+    /// Where a daemonized server logs when the config doesn't name a file
+    pub fn get_default_log_file(config: &Config, display: &str) -> Result<PathBuf, Error> {
+        get_sibling_file(config, display, "log")
+    }
+
+    pub fn get_pid_file(config: &Config, display: &str) -> Result<PathBuf, Error> {
+        get_sibling_file(config, display, "pid")
+    }
+
+    /// Where the server keeps the windows it manages while it isn't running
+    pub fn get_state_file(config: &Config, display: &str) -> Result<PathBuf, Error> {
+        get_sibling_file(config, display, "state")
+    }
+
+    pub fn write_pid_file(config: &Config, display: &str) -> Result<(), Error> {
+        let pid_path = get_pid_file(config, display)?;
+        match fs::write(pid_path, format!("{}\n", process::id())) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::io("write pid file", e)),
         }
     }
 
-    pub fn get_active_window(base: &base::Connection, ewmh: &ewmh::Connection) -> Result<(xproto::Window, i32), GenericError> {
-        let setup = base.get_setup();
-        for (screen_idx, screen) in setup.roots().enumerate() {
-            match xproto::query_pointer(base, screen.root()).get_reply() {
-                Ok(reply) => {
-                    if reply.same_screen() {
-                        #[cfg(feature = "debug")]
-                        println!("Screen: {}", screen_idx);
-                        let screen_i32 = screen_idx as i32;
-                        return Ok((get_screen_active_window(ewmh, screen_i32)?, screen_i32));
-                    }
-                },
-                Err(_) => return Err(GenericError::new("query pointer")),
+    pub fn remove_pid_file(config: &Config, display: &str) -> Result<(), Error> {
+        let pid_path = get_pid_file(config, display)?;
+        if pid_path.exists() {
+            if let Err(e) = fs::remove_file(pid_path.as_path()) {
+                return Err(Error::io("cannot remove pid file", e));
             }
         }
-        Err(GenericError::new("couldn't find active window or screen"))
+        Ok(())
     }
 
-    pub fn get_screen_active_window(ewmh: &ewmh::Connection, screen: i32) -> Result<xproto::Window, GenericError> {
-        let window_cookie = ewmh::get_active_window(ewmh, screen);
-        let window_res = window_cookie.get_reply();
-        match window_res {
-            Ok(window) => Ok(window),
-            Err(_) => Err(GenericError::new("get active window")),
-        }
+    pub fn bind_client_socket(config: &Config, display: &str) -> Result<UnixDatagram, Error> {
+        let socket_path = get_client_socket_file(config, display)?;
+        remove_client_socket_file(config, display)?;
+        bind_private(socket_path.as_path())
     }
 
-    pub fn get_parent_window(base: &base::Connection, window: xproto::Window) -> Result<xproto::Window, GenericError> {
-        let query_cookie = xproto::query_tree(base, window);
-        match query_cookie.get_reply() {
-            Ok(tree) => Ok(tree.parent()),
-            Err(_) => Err(GenericError::new("query tree")),
+    pub fn remove_client_socket_file(config: &Config, display: &str) -> Result<(), Error> {
+        let socket_path = get_client_socket_file(config, display)?;
+        if socket_path.exists() {
+            if let Err(e) = fs::remove_file(socket_path.as_path()) {
+                return Err(Error::io("cannot remove client socket", e));
+            }
         }
+        Ok(())
     }
 
-    pub fn get_root_window(base: &base::Connection, window: xproto::Window) -> Result<xproto::Window, GenericError> {
-        let query_cookie = xproto::query_tree(base, window);
-        match query_cookie.get_reply() {
-            Ok(tree) => Ok(tree.root()),
-            Err(_) => Err(GenericError::new("query tree")),
+    /// Sends a message to the server and waits for its reply
+    pub fn request(config: &Config, display: &str, message: &Request, timeout: Duration) -> Result<Response, Error> {
+        let server_path = get_socket_file(config, display)?;
+        let socket = bind_client_socket(config, display)?;
+        let reply = send_and_receive(&socket, &server_path, message, config.buffer_len, timeout);
+        remove_client_socket_file(config, display)?;
+        reply
+    }
+
+    /// Sends a request from an already bound client socket and waits for the reply
+    pub fn send_and_receive(socket: &UnixDatagram, server_path: &Path, message: &Request, buffer_len: usize,
+                        timeout: Duration) -> Result<Response, Error> {
+        let message_enc = encode_request(message)?;
+        // The server would only answer that it didn't get all of it
+        if message_enc.len() > buffer_len {
+            return Err(Error::invalid_command(&format!("request of {} bytes is larger than buffer-len", message_enc.len())));
+        }
+        if let Err(e) = socket.send_to(&message_enc, server_path) {
+            return Err(Error::io("send message to socket", e));
+        }
+        if let Err(e) = socket.set_read_timeout(Some(timeout)) {
+            return Err(Error::io("set reply timeout", e));
         }
+        // Replies are read whole, buffer_len only limits requests
+        let mut buf = vec![0; peek_datagram_size(socket, "no reply from server")?];
+        let size = match socket.recv(&mut buf) {
+            Ok(sz) => sz,
+            Err(e) => return Err(Error::io("no reply from server", e)),
+        };
+        decode_response(&buf[0..size])
     }
 
-    fn get_extents(ewmh: &ewmh::Connection, window: xproto::Window) -> Result<(u32, u32, u32, u32), GenericError> {
-        let extent_cookie = ewmh::get_frame_extents(ewmh, window);
-        let extent = match extent_cookie.get_reply() {
-            Ok(res) => res,
-            Err(_) => return Err(GenericError::new("get frame extents")),
+    /// Full size of the next datagram, which stays on the socket. Blocks like recv does.
+    pub fn peek_datagram_size(socket: &UnixDatagram, context: &str) -> Result<usize, Error> {
+        // MSG_TRUNC makes recv return the real length even though nothing fits in the buffer
+        let size = unsafe {
+            libc::recv(socket.as_raw_fd(), ptr::null_mut(), 0, libc::MSG_PEEK | libc::MSG_TRUNC)
         };
+        if size < 0 {
+            return Err(Error::last_os_error(context));
+        }
+        Ok(size as usize)
+    }
 
-        // For some reason, these signify (left, right, top, bottom).
-        //   xprop exhibits this order properly, so I don't know whether it's a Rust binding issue
-        //   or an xcb issue.
-        Ok((extent.top(), extent.bottom(), extent.left(), extent.right()))
-        // Ok((extent.left(), extent.right(), extent.top(), extent.bottom()))
+    /// Binds the server socket and asks the kernel to attach the sender's credentials to
+    /// every datagram, see recv_with_credentials
+    pub fn bind_socket(config: &Config, display: &str) -> Result<UnixDatagram, Error> {
+        let socket_path = get_socket_dir(config, display)?;
+        remove_socket_file(config, display)?;
+        let socket = bind_private(socket_path.as_path())?;
+        enable_credentials(&socket)?;
+        Ok(socket)
     }
 
-    pub fn get_geometry(base: &base::Connection, ewmh: &ewmh::Connection, window: xproto::Window) -> Result<(i16, i16, u16, u16), GenericError> {
-        let geo_cookie = xproto::get_geometry(base, window);
-        let geo = match geo_cookie.get_reply() {
-            Ok(reply) => reply,
-            Err(_) => return Err(GenericError::new("get window geometry")),
+    fn enable_credentials(socket: &UnixDatagram) -> Result<(), Error> {
+        let enable: libc::c_int = 1;
+        let res = unsafe {
+            libc::setsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PASSCRED,
+                             &enable as *const libc::c_int as *const libc::c_void,
+                             mem::size_of::<libc::c_int>() as libc::socklen_t)
         };
+        if res < 0 {
+            return Err(Error::last_os_error("enable socket credentials"));
+        }
+        Ok(())
+    }
 
-        // let parent = get_parent_window(base, window)?;
-        let translate_cookie = xproto::translate_coordinates(&base, window, geo.root(), geo.x(), geo.y());
-        let translate = match translate_cookie.get_reply() {
-            Ok(res) => res,
-            Err(_) => return Err(GenericError::new("translate parent's coordinates")),
+    /// The datagram socket passed by systemd socket activation, if any. The unit's
+    /// ListenDatagram should be the path clients use, e.g. %t/sss_socket_0 for display :0.
+    pub fn get_activated_socket() -> Result<Option<UnixDatagram>, Error> {
+        let listen_pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+        let listen_fds = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<u32>().ok());
+        // Children we spawn must not think the sockets are meant for them
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+        match (listen_pid, listen_fds) {
+            (Some(pid), Some(fds)) if pid == process::id() && fds > 0 => (),
+            _ => return Ok(None),
+        }
+        if listen_fds != Some(1) {
+            return Err(Error::config("expected a single socket from LISTEN_FDS"));
+        }
+        let fd = LISTEN_FDS_START;
+        let mut socket_type: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE,
+                             &mut socket_type as *mut libc::c_int as *mut libc::c_void, &mut len)
         };
-        let (ext_left, ext_right, ext_top, ext_bottom) = get_extents(ewmh, window)?;
-        Ok((
-            translate.dst_x() - 2 * ext_left as i16,
-            translate.dst_y() - 2 * ext_top as i16,
-            geo.width() + (ext_left + ext_right) as u16,
-            geo.height() + (ext_top + ext_bottom) as u16,
-        ))
+        if res < 0 || socket_type != libc::SOCK_DGRAM {
+            return Err(Error::config("activated socket is not a datagram socket"));
+        }
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        let socket = unsafe { UnixDatagram::from_raw_fd(fd) };
+        enable_credentials(&socket)?;
+        Ok(Some(socket))
     }
 
-    pub fn get_desktop_geometry(ewmh: &ewmh::Connection, screen: i32) -> Result<(u32, u32), GenericError> {
-        let desktop_cookie = ewmh::get_desktop_geometry(ewmh, screen);
-        match desktop_cookie.get_reply() {
-            Ok(res) => Ok(res),
-            Err(_) => Err(GenericError::new("get desktop geometry")),
-        }
+    /// Who sent a datagram, the path is None for senders that didn't bind their socket
+    pub struct Sender {
+        pub path: Option<PathBuf>,
+        pub uid: libc::uid_t,
     }
 
-    fn get_desktop_idx(ewmh: &ewmh::Connection, screen: i32) -> Result<u32, GenericError> {
-        let desktop_cookie = ewmh::get_current_desktop(ewmh, screen);
-        match desktop_cookie.get_reply() {
-            Ok(res) => Ok(res),
-            Err(_) => Err(GenericError::new("get current desktop")),
+    impl Sender {
+        pub fn is_same_user(&self) -> bool {
+            self.uid == unsafe { libc::getuid() }
         }
     }
 
-    pub fn get_work_area(ewmh: &ewmh::Connection, screen: i32) -> Result<(i16, i16, u16, u16), GenericError> {
-        let area_cookie = ewmh::get_work_area(ewmh, screen);
-        let areas = match area_cookie.get_reply() {
-            Ok(res) => res,
-            Err(_) => return Err(GenericError::new("get work area")),
-        };
-        let idx = get_desktop_idx(ewmh, screen)? as usize;
-        match areas.work_area().get(idx) {
-            Some(area) => Ok((
-                area.x() as i16,
-                area.y() as i16,
-                area.width() as u16,
-                area.height() as u16
-            )),
-            None => Err(GenericError::new("couldn't find work area for screen")),
-        }
-    }
-
-    pub fn move_resize(base: &base::Connection, ewmh: &ewmh::Connection, window: xproto::Window,
-                       x: i16, y: i16, width: u16, height: u16) -> Result<(), GenericError> {
-        let (ext_left, ext_right, ext_top, ext_bottom) = get_extents(ewmh, window)?;
-        let width = width - (ext_left + ext_right) as u16;
-        let height = height - (ext_top + ext_bottom) as u16;
-        let value_list = [
-            (xproto::CONFIG_WINDOW_X as u16, x as u32),
-            (xproto::CONFIG_WINDOW_Y as u16, y as u32),
-            (xproto::CONFIG_WINDOW_WIDTH as u16, width as u32),
-            (xproto::CONFIG_WINDOW_HEIGHT as u16, height as u32),
-        ];
-        // let value_list = [(200, xproto::CONFIG_WINDOW_HEIGHT)];
-        let cookie = xproto::configure_window(base, window, &value_list);
-        match cookie.request_check() {
-            Ok(_) => Ok(()),
-            Err(_) => Err(GenericError::new("move and resize window")),
+    /// recv_from that also returns the sender's uid, std can't read SCM_CREDENTIALS yet
+    pub fn recv_with_credentials(socket: &UnixDatagram, buf: &mut [u8]) -> Result<(usize, Sender), Error> {
+        let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec{iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len()};
+        // u64s keep the control buffer aligned for cmsghdr
+        let mut control = [0u64; 8];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut addr as *mut libc::sockaddr_un as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+        let size = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+        if size < 0 {
+            return Err(Error::last_os_error("receive from socket"));
         }
-    }
 
-    pub fn ewmh_restore(ewmh: &ewmh::Connection, window: xproto::Window, screen: i32) -> Result<(), GenericError> {
-        let cookie = ewmh::request_change_wm_state(
+        let mut uid = None;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_CREDENTIALS {
+                    let credentials = (libc::CMSG_DATA(cmsg) as *const libc::ucred).read_unaligned();
+                    uid = Some(credentials.uid);
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        let uid = match uid {
+            Some(uid) => uid,
+            None => return Err(Error::invalid_command("datagram without credentials")),
+        };
+
+        // Unbound and abstract addresses have no path to reply to
+        let path_offset = mem::size_of::<libc::sa_family_t>();
+        let path_len = (msg.msg_namelen as usize).saturating_sub(path_offset);
+        let path_bytes: Vec<u8> = addr.sun_path[..path_len.min(addr.sun_path.len())].iter()
+            .map(|c| *c as u8)
+            .take_while(|c| *c != 0)
+            .collect();
+        let path = match path_bytes.is_empty() {
+            true => None,
+            false => Some(PathBuf::from(OsStr::from_bytes(&path_bytes))),
+        };
+        Ok((size as usize, Sender{path, uid}))
+    }
+}
+
+pub mod xcb {
+    use crate::errors::Error;
+    use crate::commands::StateAction;
+    use crate::config::KeyBinding;
+    use xcb_util::ewmh;
+    use xcb_util::ffi::icccm;
+    use xcb::base;
+    use xcb::xinerama;
+    use xcb::xproto;
+    use serde::{Deserialize, Serialize};
+    use zvariant_derive::Type;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::process;
+    use std::thread;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    const DECORATION_POLL_ATTEMPTS: u32 = 20;
+    const DECORATION_POLL_MS: u64 = 10;
+    
+    // EWMH and core requests share one connection, which the X server handles in the order the
+    // requests were sent, and all events arrive on it too. What the window manager does about our
+    // client messages still happens later, that's why decorations are polled.
+    pub struct XCBConnections {
+        pub ewmh: ewmh::Connection,
+        pub screen: i32,
+        cache: RefCell<XCache>,
+    }
+
+    // Atoms never change while we're connected, work areas are dropped when the root window
+    // properties they come from change
+    #[derive(Default)]
+    struct XCache {
+        atoms: HashMap<String, xproto::Atom>,
+        work_areas: HashMap<i32, Rect>,
+        monitors: HashMap<i32, Vec<Rect>>,
+        supported: HashMap<i32, Vec<xproto::Atom>>,
+    }
+
+    impl XCBConnections {
+        pub fn base(&self) -> &base::Connection {
+            &self.ewmh
+        }
+
+        /// Like get_atom, but only asks the server once per connection
+        pub fn atom(&self, name: &str) -> Result<xproto::Atom, Error> {
+            if let Some(atom) = self.cache.borrow().atoms.get(name) {
+                return Ok(*atom);
+            }
+            let atom = get_atom(self.base(), name)?;
+            self.cache.borrow_mut().atoms.insert(name.to_string(), atom);
+            Ok(atom)
+        }
+
+        /// Forgets what the PropertyNotify for this atom made stale, the event loop calls this
+        /// before running commands
+        pub fn property_changed(&self, atom: xproto::Atom) {
+            // Window managers update the work area when monitors come and go
+            if atom == self.ewmh.WORKAREA() || atom == self.ewmh.CURRENT_DESKTOP() {
+                let mut cache = self.cache.borrow_mut();
+                cache.work_areas.clear();
+                cache.monitors.clear();
+            }
+            if atom == self.ewmh.SUPPORTED() {
+                self.cache.borrow_mut().supported.clear();
+            }
+        }
+    }
+    
+    pub fn get_screen_root_window(base: &base::Connection, screen: i32) -> Result<xproto::Window, Error> {
+        let setup = base.get_setup();
+        match setup.roots().nth(screen as usize) {
+            Some(screen) => Ok(screen.root()),
+            None => Err(Error::window("iterating through screens")),
+        }
+    }
+
+    /// Index of the screen whose root window is an ancestor of the window
+    pub fn get_window_screen(base: &base::Connection, window: xproto::Window) -> Result<i32, Error> {
+        let root = get_root_window(base, window)?;
+        let setup = base.get_setup();
+        match setup.roots().position(|screen| screen.root() == root) {
+            Some(idx) => Ok(idx as i32),
+            None => Err(Error::window("couldn't find screen of window")),
+        }
+    }
+
+    pub fn get_active_window(base: &base::Connection, ewmh: &ewmh::Connection) -> Result<(xproto::Window, i32), Error> {
+        let setup = base.get_setup();
+        // Ask every screen at once instead of waiting for each answer in turn
+        let pointer_cookies: Vec<_> = setup.roots().map(|screen| xproto::query_pointer(base, screen.root())).collect();
+        for (screen_idx, pointer_cookie) in pointer_cookies.into_iter().enumerate() {
+            let reply = check_reply(base, "query pointer", pointer_cookie.get_reply())?;
+            if reply.same_screen() {
+                #[cfg(feature = "debug")]
+                println!("Screen: {}", screen_idx);
+                let screen_i32 = screen_idx as i32;
+                return Ok((get_screen_active_window(ewmh, screen_i32)?, screen_i32));
+            }
+        }
+        Err(Error::window("couldn't find active window or screen"))
+    }
+
+    pub fn get_screen_active_window(ewmh: &ewmh::Connection, screen: i32) -> Result<xproto::Window, Error> {
+        let window_cookie = ewmh::get_active_window(ewmh, screen);
+        let window_res = window_cookie.get_reply();
+        match window_res {
+            Ok(window) => Ok(window),
+            Err(e) => Err(Error::x11(ewmh, "get active window", e)),
+        }
+    }
+
+    pub fn get_parent_window(base: &base::Connection, window: xproto::Window) -> Result<xproto::Window, Error> {
+        let query_cookie = xproto::query_tree(base, window);
+        let tree = check_reply(base, "query tree", query_cookie.get_reply())?;
+        Ok(tree.parent())
+    }
+
+    pub fn get_root_window(base: &base::Connection, window: xproto::Window) -> Result<xproto::Window, Error> {
+        let query_cookie = xproto::query_tree(base, window);
+        let tree = check_reply(base, "query tree", query_cookie.get_reply())?;
+        Ok(tree.root())
+    }
+
+    pub fn get_atom(base: &base::Connection, name: &str) -> Result<xproto::Atom, Error> {
+        let atom_cookie = xproto::intern_atom(base, false, name);
+        let reply = check_reply(base, "intern atom", atom_cookie.get_reply())?;
+        Ok(reply.atom())
+    }
+
+    // Core replies come back empty instead of failing once the connection is gone, and reading
+    // from an empty reply crashes, so every core reply is checked here before it's used
+    fn check_reply<T>(base: &base::Connection, context: &str,
+                      reply: Result<base::Reply<T>, xcb::GenericError>) -> Result<base::Reply<T>, Error> {
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(e) => return Err(Error::x11(base, context, e)),
+        };
+        match (base.has_error(), reply.ptr.is_null()) {
+            (Err(source), _) => Err(Error::connection(context, Some(source))),
+            (Ok(_), true) => Err(Error::connection(context, None)),
+            (Ok(_), false) => Ok(reply),
+        }
+    }
+
+    // Returns fewer than `len` values (usually none) when the property is missing or shorter
+    fn get_property_values(base: &base::Connection, window: xproto::Window, atom: xproto::Atom,
+                     type_: xproto::Atom, len: u32) -> Result<Vec<u32>, Error> {
+        property_values(base, xproto::get_property(base, false, window, atom, type_, 0, len))
+    }
+
+    // The reply half of get_property_values, for requests that were sent together
+    fn property_values(base: &base::Connection, property_cookie: xproto::GetPropertyCookie) -> Result<Vec<u32>, Error> {
+        let property = check_reply(base, "get window property", property_cookie.get_reply())?;
+        if property.format() != 32 {
+            return Ok(Vec::new());
+        }
+        Ok(property.value::<u32>().to_vec())
+    }
+
+    /// A window or screen area in root coordinates
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, Type)]
+    pub struct Rect {
+        pub x: i16,
+        pub y: i16,
+        pub width: u16,
+        pub height: u16,
+    }
+
+    impl Rect {
+        /// The area covered by both rectangles, None when they don't overlap
+        pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+            let left = (self.x as i32).max(other.x as i32);
+            let top = (self.y as i32).max(other.y as i32);
+            let right = (self.x as i32 + self.width as i32).min(other.x as i32 + other.width as i32);
+            let bottom = (self.y as i32 + self.height as i32).min(other.y as i32 + other.height as i32);
+            match right > left && bottom > top {
+                true => Some(Rect{x: left as i16, y: top as i16, width: (right - left) as u16, height: (bottom - top) as u16}),
+                false => None,
+            }
+        }
+    }
+
+    /// Decoration widths around a window, stored in the EWMH order (left, right, top, bottom)
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub struct Extents {
+        pub left: u32,
+        pub right: u32,
+        pub top: u32,
+        pub bottom: u32,
+    }
+
+    // Frame extents are written by other clients and nothing stops them from being garbage,
+    // no decoration can be wider than the largest window
+    const MAX_EXTENT: u32 = u16::MAX as u32;
+
+    impl Extents {
+        // None when any of the values can't be a real decoration
+        fn from_cardinals(values: &[u32]) -> Option<Extents> {
+            if values.iter().any(|value| *value > MAX_EXTENT) {
+                return None;
+            }
+            Some(Extents{left: values[0], right: values[1], top: values[2], bottom: values[3]})
+        }
+
+        fn uniform(width: u32) -> Extents {
+            Extents{left: width, right: width, top: width, bottom: width}
+        }
+    }
+
+    /// Everything between a client window and what the user sees as the window.
+    /// `frame` is drawn by the window manager around the client, while `client` is the
+    /// invisible margin (shadows, resize handles) client-side decorated apps draw inside
+    /// their own window.
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub struct Decorations {
+        pub frame: Extents,
+        pub client: Extents,
+    }
+
+    impl Decorations {
+        /// Offset from the visible top left corner to the client window's origin
+        pub fn client_offset(&self) -> (i16, i16) {
+            (
+                clamp_coordinate(self.frame.left as i64 - self.client.left as i64),
+                clamp_coordinate(self.frame.top as i64 - self.client.top as i64),
+            )
+        }
+
+        /// How much wider and taller the visible window is than the client window
+        pub fn size_difference(&self) -> (i32, i32) {
+            let difference = |frame: i64, client: i64| (frame - client).clamp(-(u16::MAX as i64), u16::MAX as i64) as i32;
+            (
+                difference(self.frame.left as i64 + self.frame.right as i64, self.client.left as i64 + self.client.right as i64),
+                difference(self.frame.top as i64 + self.frame.bottom as i64, self.client.top as i64 + self.client.bottom as i64),
+            )
+        }
+
+        /// Visible geometry of a client window located at the given root coordinates
+        pub fn outer_geometry(&self, client: Rect) -> Rect {
+            let (offset_x, offset_y) = self.client_offset();
+            let (diff_width, diff_height) = self.size_difference();
+            Rect{
+                x: client.x.saturating_sub(offset_x),
+                y: client.y.saturating_sub(offset_y),
+                width: clamp_dimension(client.width as i32 + diff_width),
+                height: clamp_dimension(client.height as i32 + diff_height),
+            }
+        }
+
+        /// Client window geometry that results in the given visible geometry
+        pub fn client_geometry(&self, outer: Rect) -> Rect {
+            let (offset_x, offset_y) = self.client_offset();
+            let (diff_width, diff_height) = self.size_difference();
+            Rect{
+                x: outer.x.saturating_add(offset_x),
+                y: outer.y.saturating_add(offset_y),
+                width: clamp_dimension(outer.width as i32 - diff_width),
+                height: clamp_dimension(outer.height as i32 - diff_height),
+            }
+        }
+    }
+
+    fn clamp_dimension(value: i32) -> u16 {
+        value.max(1).min(u16::MAX as i32) as u16
+    }
+
+    fn clamp_coordinate(value: i64) -> i16 {
+        value.clamp(i16::MIN as i64, i16::MAX as i64) as i16
+    }
+
+    // Walks up the tree until the child of the root window, which is the frame a
+    // reparenting window manager wraps the client in (or the client itself otherwise).
+    // Returns the root window along with it.
+    pub fn get_frame_window(base: &base::Connection, window: xproto::Window) -> Result<(xproto::Window, xproto::Window), Error> {
+        let mut current = window;
+        loop {
+            let query_cookie = xproto::query_tree(base, current);
+            let tree = check_reply(base, "query tree", query_cookie.get_reply())?;
+            if tree.parent() == tree.root() || tree.parent() == xcb::NONE {
+                return Ok((current, tree.root()));
+            }
+            current = tree.parent();
+        }
+    }
+
+    // The geometry and translate_coordinates requests, which can go out together once the
+    // root window is known
+    struct RootGeometryCookies<'a> {
+        geometry: xproto::GetGeometryCookie<'a>,
+        translate: xproto::TranslateCoordinatesCookie<'a>,
+    }
+
+    fn request_root_geometry<'a>(base: &'a base::Connection, window: xproto::Window, root: xproto::Window) -> RootGeometryCookies<'a> {
+        RootGeometryCookies{
+            geometry: xproto::get_geometry(base, window),
+            translate: xproto::translate_coordinates(base, window, root, 0, 0),
+        }
+    }
+
+    // Geometry of the window's inside in root coordinates, plus its border width
+    fn collect_root_geometry(base: &base::Connection, cookies: RootGeometryCookies) -> Result<(Rect, u16), Error> {
+        let geo = check_reply(base, "get window geometry", cookies.geometry.get_reply())?;
+        let translate = check_reply(base, "translate coordinates to root", cookies.translate.get_reply())?;
+        let rect = Rect{x: translate.dst_x(), y: translate.dst_y(), width: geo.width(), height: geo.height()};
+        Ok((rect, geo.border_width()))
+    }
+
+    // Used when the window manager doesn't publish _NET_FRAME_EXTENTS: compare the client
+    // against its frame window, or just count the X border for non-reparenting managers
+    fn get_frame_window_extents(base: &base::Connection, window: xproto::Window) -> Result<Extents, Error> {
+        let (frame_window, root) = get_frame_window(base, window)?;
+        let client_cookies = request_root_geometry(base, window, root);
+        if frame_window == window {
+            let (_, border) = collect_root_geometry(base, client_cookies)?;
+            return Ok(Extents::uniform(border as u32));
+        }
+        let frame_cookies = request_root_geometry(base, frame_window, root);
+        let (client, _) = collect_root_geometry(base, client_cookies)?;
+        let (frame, frame_border) = collect_root_geometry(base, frame_cookies)?;
+        Ok(frame_extents(client, frame, frame_border))
+    }
+
+    // Space between a client's inside and the outside of the frame window around it
+    fn frame_extents(client: Rect, frame: Rect, frame_border: u16) -> Extents {
+        let frame_border = frame_border as i32;
+        let left = client.x as i32 - frame.x as i32 + frame_border;
+        let top = client.y as i32 - frame.y as i32 + frame_border;
+        let right = (frame.x as i32 + frame.width as i32) - (client.x as i32 + client.width as i32) + frame_border;
+        let bottom = (frame.y as i32 + frame.height as i32) - (client.y as i32 + client.height as i32) + frame_border;
+        Extents{
+            left: left.max(0) as u32,
+            right: right.max(0) as u32,
+            top: top.max(0) as u32,
+            bottom: bottom.max(0) as u32,
+        }
+    }
+
+    // The frame extents and _GTK_FRAME_EXTENTS requests, sent before waiting on either
+    struct DecorationsCookies<'a> {
+        frame: xproto::GetPropertyCookie<'a>,
+        client: xproto::GetPropertyCookie<'a>,
+    }
+
+    fn request_decorations<'a>(connections: &'a XCBConnections, window: xproto::Window) -> Result<DecorationsCookies<'a>, Error> {
+        let base = connections.base();
+        // ewmh::get_frame_extents isn't used since xcb-util's Extents declares its fields in a
+        // different order than the C struct, so the values come back shuffled
+        let frame_atom = connections.ewmh.FRAME_EXTENTS();
+        let client_atom = connections.atom("_GTK_FRAME_EXTENTS")?;
+        Ok(DecorationsCookies{
+            frame: xproto::get_property(base, false, window, frame_atom, xproto::ATOM_CARDINAL, 0, 4),
+            client: xproto::get_property(base, false, window, client_atom, xproto::ATOM_CARDINAL, 0, 4),
+        })
+    }
+
+    fn collect_decorations(connections: &XCBConnections, window: xproto::Window,
+                           cookies: DecorationsCookies) -> Result<Decorations, Error> {
+        let frame_values = property_values(connections.base(), cookies.frame)?;
+        let client_values = property_values(connections.base(), cookies.client)?;
+        let frame = match frame_values.len() {
+            4 => Extents::from_cardinals(&frame_values),
+            _ => None,
+        };
+        let frame = match frame {
+            Some(frame) => frame,
+            None => get_frame_window_extents(connections.base(), window)?,
+        };
+        let client = match client_values.len() {
+            4 => Extents::from_cardinals(&client_values),
+            _ => None,
+        };
+        let client = client.unwrap_or_default();
+        Ok(Decorations{frame, client})
+    }
+
+    pub fn get_decorations(connections: &XCBConnections, window: xproto::Window) -> Result<Decorations, Error> {
+        let cookies = request_decorations(connections, window)?;
+        collect_decorations(connections, window, cookies)
+    }
+
+    /// What commands need to know about a window, asked for in a single round trip
+    pub struct WindowInfo {
+        pub window: xproto::Window,
+        pub screen: i32,
+        /// One of WINDOW_TYPES
+        pub window_type: &'static str,
+        /// Visible geometry in root coordinates, decorations included
+        pub geometry: Rect,
+        pub decorations: Decorations,
+        pub size_hints: SizeHints,
+        /// The atoms in _NET_WM_STATE
+        pub states: Vec<xproto::Atom>,
+    }
+
+    pub fn get_window_info(connections: &XCBConnections, window: xproto::Window, screen: i32) -> Result<WindowInfo, Error> {
+        let base = connections.base();
+        let ewmh = &connections.ewmh;
+        let root = get_screen_root_window(base, screen)?;
+        // Every request goes out before we wait on the first reply
+        let type_cookie = xproto::get_property(base, false, window, ewmh.WM_WINDOW_TYPE(), xproto::ATOM_ATOM, 0, 32);
+        let transient_cookie = request_transient_for(base, window);
+        let state_cookie = xproto::get_property(base, false, window, ewmh.WM_STATE(), xproto::ATOM_ATOM, 0, 32);
+        let hints_cookie = request_size_hints(base, window);
+        let decorations_cookies = request_decorations(connections, window)?;
+        let geometry_cookies = request_root_geometry(base, window, root);
+        let window_type = window_type(ewmh, &property_values(base, type_cookie)?, &property_values(base, transient_cookie)?);
+        let states = property_values(base, state_cookie)?;
+        let size_hints = SizeHints::from_property(&property_values(base, hints_cookie)?);
+        let (client, _border) = collect_root_geometry(base, geometry_cookies)?;
+        let decorations = collect_decorations(connections, window, decorations_cookies)?;
+        Ok(WindowInfo{
+            window,
+            screen,
+            window_type,
+            geometry: decorations.outer_geometry(client),
+            decorations,
+            size_hints,
+            states,
+        })
+    }
+
+    /// Window types that can show up in _NET_WM_WINDOW_TYPE, without the prefix
+    pub const WINDOW_TYPES: [&str; 14] = [
+        "desktop", "dock", "toolbar", "menu", "utility", "splash", "dialog", "dropdown_menu",
+        "popup_menu", "tooltip", "notification", "combo", "dnd", "normal",
+    ];
+
+    fn window_type_atoms(ewmh: &ewmh::Connection) -> [(&'static str, xproto::Atom); 14] {
+        [
+            ("desktop", ewmh.WM_WINDOW_TYPE_DESKTOP()),
+            ("dock", ewmh.WM_WINDOW_TYPE_DOCK()),
+            ("toolbar", ewmh.WM_WINDOW_TYPE_TOOLBAR()),
+            ("menu", ewmh.WM_WINDOW_TYPE_MENU()),
+            ("utility", ewmh.WM_WINDOW_TYPE_UTILITY()),
+            ("splash", ewmh.WM_WINDOW_TYPE_SPLASH()),
+            ("dialog", ewmh.WM_WINDOW_TYPE_DIALOG()),
+            ("dropdown_menu", ewmh.WM_WINDOW_TYPE_DROPDOWN_MENU()),
+            ("popup_menu", ewmh.WM_WINDOW_TYPE_POPUP_MENU()),
+            ("tooltip", ewmh.WM_WINDOW_TYPE_TOOLTIP()),
+            ("notification", ewmh.WM_WINDOW_TYPE_NOTIFICATION()),
+            ("combo", ewmh.WM_WINDOW_TYPE_COMBO()),
+            ("dnd", ewmh.WM_WINDOW_TYPE_DND()),
+            ("normal", ewmh.WM_WINDOW_TYPE_NORMAL()),
+        ]
+    }
+
+    fn request_transient_for<'a>(base: &'a base::Connection, window: xproto::Window) -> xproto::GetPropertyCookie<'a> {
+        xproto::get_property(base, false, window, xproto::ATOM_WM_TRANSIENT_FOR, xproto::ATOM_WINDOW, 0, 1)
+    }
+
+    fn window_type(ewmh: &ewmh::Connection, types: &[xproto::Atom], transient_for: &[xproto::Window]) -> &'static str {
+        // The list is ordered by preference, the first type we know about wins
+        let known_types = window_type_atoms(ewmh);
+        for atom in types.iter() {
+            if let Some((name, _)) = known_types.iter().find(|(_, known)| known == atom) {
+                return name;
+            }
+        }
+        // EWMH: untyped windows are dialogs if they're transient for another window
+        match transient_for.is_empty() {
+            true => "normal",
+            false => "dialog",
+        }
+    }
+
+    const SESSION_PROPERTY: &str = "_SSS_SESSION";
+
+    /// Names the X server we're connected to, window ids only mean something within one X
+    /// server and the next one hands out the same ids again
+    pub fn get_session_token(connections: &XCBConnections) -> Result<String, Error> {
+        let base = connections.base();
+        let root = get_screen_root_window(base, connections.screen)?;
+        let session = connections.atom(SESSION_PROPERTY)?;
+        let token = get_property_string(base, root, session, xproto::ATOM_STRING)?;
+        if !token.is_empty() {
+            return Ok(token);
+        }
+        // The root window lives exactly as long as the X server, the first of us to connect
+        // names it
+        let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let token = format!("{}-{}", process::id(), elapsed.as_nanos());
+        let cookie = xproto::change_property_checked(
+            base,
+            xproto::PROP_MODE_REPLACE as u8,
+            root,
+            session,
+            xproto::ATOM_STRING,
+            8,
+            token.as_bytes(),
+        );
+        match cookie.request_check() {
+            Ok(_) => Ok(token),
+            Err(e) => Err(Error::x11(base, "set session token", e)),
+        }
+    }
+
+    const MAX_STRING_PROPERTY_LEN: u32 = 1024;
+
+    // Text properties are read as raw bytes, an empty string means the property is missing
+    fn get_property_string(base: &base::Connection, window: xproto::Window, atom: xproto::Atom,
+                           type_: xproto::Atom) -> Result<String, Error> {
+        let property_cookie = xproto::get_property(base, false, window, atom, type_, 0, MAX_STRING_PROPERTY_LEN);
+        let property = check_reply(base, "get window property", property_cookie.get_reply())?;
+        if property.format() != 8 {
+            return Ok(String::new());
+        }
+        Ok(String::from_utf8_lossy(property.value::<u8>()).into_owned())
+    }
+
+    /// The instance and class names from WM_CLASS
+    pub fn get_wm_class(base: &base::Connection, window: xproto::Window) -> Result<Vec<String>, Error> {
+        let class = get_property_string(base, window, xproto::ATOM_WM_CLASS, xproto::ATOM_STRING)?;
+        Ok(class.split('\0').filter(|name| !name.is_empty()).map(String::from).collect())
+    }
+
+    /// _NET_WM_NAME, or WM_NAME for clients that don't set it
+    pub fn get_window_title(connections: &XCBConnections, window: xproto::Window) -> Result<String, Error> {
+        let base = connections.base();
+        let utf8_string = connections.atom("UTF8_STRING")?;
+        let title = get_property_string(base, window, connections.ewmh.WM_NAME(), utf8_string)?;
+        if !title.is_empty() {
+            return Ok(title);
+        }
+        get_property_string(base, window, xproto::ATOM_WM_NAME, xproto::ATOM_ANY)
+    }
+
+    pub fn get_window_role(connections: &XCBConnections, window: xproto::Window) -> Result<String, Error> {
+        let role_atom = connections.atom("WM_WINDOW_ROLE")?;
+        get_property_string(connections.base(), window, role_atom, xproto::ATOM_STRING)
+    }
+
+    /// The managed windows, none if the window manager doesn't publish _NET_CLIENT_LIST
+    pub fn get_client_list(ewmh: &ewmh::Connection, screen: i32) -> Result<Vec<xproto::Window>, Error> {
+        match ewmh::get_client_list(ewmh, screen).get_reply() {
+            Ok(res) => Ok(res.windows().to_vec()),
+            Err(e) => match Error::x11(ewmh, "get client list", e) {
+                Error::PropertyMissing(_) => Ok(Vec::new()),
+                error => Err(error),
+            },
+        }
+    }
+
+    /// Asks for PropertyNotify events on every root window so new clients show up in _NET_CLIENT_LIST
+    pub fn watch_client_list(base: &base::Connection) -> Result<(), Error> {
+        for screen in base.get_setup().roots() {
+            let cookie = xproto::change_window_attributes_checked(
+                base, screen.root(), &[(xproto::CW_EVENT_MASK, xproto::EVENT_MASK_PROPERTY_CHANGE)]);
+            if let Err(e) = cookie.request_check() {
+                return Err(Error::x11(base, "watch root window properties", e));
+            }
+        }
+        Ok(())
+    }
+
+    /// How split rectangles are adjusted to the sizes a window accepts
+    #[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum SizeHintPolicy {
+        /// Shrink to an accepted size and center it inside the rectangle
+        Center,
+        /// Shrink to an accepted size anchored to the rectangle's top left corner
+        Shrink,
+        /// Use the rectangle as is and let the window manager sort it out
+        Ignore,
+    }
+
+    /// The size constraints of ICCCM WM_NORMAL_HINTS, in client window pixels
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub struct SizeHints {
+        pub min_size: Option<(u32, u32)>,
+        pub max_size: Option<(u32, u32)>,
+        pub base_size: Option<(u32, u32)>,
+        pub increment: Option<(u32, u32)>,
+        pub aspect: Option<((u32, u32), (u32, u32))>,
+        pub gravity: Option<u32>,
+    }
+
+    impl SizeHints {
+        // Pre-ICCCM clients only set the first 15 fields
+        fn from_property(values: &[u32]) -> SizeHints {
+            match values.len() >= 15 {
+                true => SizeHints::from_cardinals(values),
+                false => SizeHints::default(),
+            }
+        }
+
+        fn from_cardinals(values: &[u32]) -> SizeHints {
+            let flags = values[0];
+            let pair = |flag: u32, idx: usize| match flags & flag != 0 && values.len() > idx + 1 {
+                true => Some((values[idx], values[idx + 1])),
+                false => None,
+            };
+            SizeHints{
+                min_size: pair(icccm::XCB_ICCCM_SIZE_HINT_P_MIN_SIZE, 5),
+                max_size: pair(icccm::XCB_ICCCM_SIZE_HINT_P_MAX_SIZE, 7),
+                increment: pair(icccm::XCB_ICCCM_SIZE_HINT_P_RESIZE_INC, 9),
+                aspect: match (pair(icccm::XCB_ICCCM_SIZE_HINT_P_ASPECT, 11), pair(icccm::XCB_ICCCM_SIZE_HINT_P_ASPECT, 13)) {
+                    (Some(min), Some(max)) => Some((min, max)),
+                    _ => None,
+                },
+                base_size: pair(icccm::XCB_ICCCM_SIZE_HINT_BASE_SIZE, 15),
+                gravity: match flags & icccm::XCB_ICCCM_SIZE_HINT_P_WIN_GRAVITY != 0 && values.len() > 17 {
+                    true => Some(values[17]),
+                    false => None,
+                },
+            }
+        }
+
+        /// Largest client size that fits inside the given one and satisfies the hints,
+        /// except for the minimum size which always wins
+        pub fn constrain(&self, width: u16, height: u16) -> (u16, u16) {
+            let (mut width, mut height) = (width as u64, height as u64);
+            // ICCCM 4.1.2.3: each of the base and minimum sizes stands in for the other
+            let (base_width, base_height) = match self.base_size.or(self.min_size) {
+                Some((w, h)) => (w as u64, h as u64),
+                None => (0, 0),
+            };
+            if let Some((max_width, max_height)) = self.max_size {
+                if max_width > 0 {
+                    width = width.min(max_width as u64);
+                }
+                if max_height > 0 {
+                    height = height.min(max_height as u64);
+                }
+            }
+            if let Some(((min_num, min_den), (max_num, max_den))) = self.aspect {
+                // Aspect ratios only cover the part of the window above the base size
+                let (aspect_width, aspect_height) = match self.base_size {
+                    Some((w, h)) => (w as u64, h as u64),
+                    None => (0, 0),
+                };
+                let mut free_width = width.saturating_sub(aspect_width);
+                let mut free_height = height.saturating_sub(aspect_height);
+                if min_num > 0 && min_den > 0 && free_width * (min_den as u64) < free_height * (min_num as u64) {
+                    free_height = free_width * min_den as u64 / min_num as u64;
+                }
+                if max_num > 0 && max_den > 0 && free_width * (max_den as u64) > free_height * (max_num as u64) {
+                    free_width = free_height * max_num as u64 / max_den as u64;
+                }
+                width = free_width + aspect_width;
+                height = free_height + aspect_height;
+            }
+            if let Some((inc_width, inc_height)) = self.increment {
+                if inc_width > 1 && width > base_width {
+                    width -= (width - base_width) % inc_width as u64;
+                }
+                if inc_height > 1 && height > base_height {
+                    height -= (height - base_height) % inc_height as u64;
+                }
+            }
+            if let Some((min_width, min_height)) = self.min_size.or(self.base_size) {
+                width = width.max(min_width as u64);
+                height = height.max(min_height as u64);
+            }
+            (clamp_dimension(width.min(i32::MAX as u64) as i32), clamp_dimension(height.min(i32::MAX as u64) as i32))
+        }
+    }
+
+    fn request_size_hints<'a>(base: &'a base::Connection, window: xproto::Window) -> xproto::GetPropertyCookie<'a> {
+        // icccm::get_wm_normal_hints isn't used since xcb-util's SizeHints accessors compare
+        // the masked flags against 1, which makes every hint but the user position disappear
+        xproto::get_property(base, false, window, xproto::ATOM_WM_NORMAL_HINTS, xproto::ATOM_WM_SIZE_HINTS,
+                             0, icccm::XCB_ICCCM_NUM_WM_SIZE_HINTS_ELEMENTS)
+    }
+
+    /// Adjusts a visible rectangle to the sizes the window accepts, placing the result
+    /// inside the original rectangle according to the policy
+    pub fn fit_size_hints(decorations: Decorations, hints: &SizeHints, policy: SizeHintPolicy, rect: Rect) -> Rect {
+        if policy == SizeHintPolicy::Ignore {
+            return rect;
+        }
+        let mut client = decorations.client_geometry(rect);
+        let (client_width, client_height) = hints.constrain(client.width, client.height);
+        client.width = client_width;
+        client.height = client_height;
+        let fit = decorations.outer_geometry(client);
+        match policy {
+            SizeHintPolicy::Center => Rect{
+                x: rect.x + ((rect.width as i32 - fit.width as i32) / 2) as i16,
+                y: rect.y + ((rect.height as i32 - fit.height as i32) / 2) as i16,
+                width: fit.width,
+                height: fit.height,
+            },
+            _ => Rect{x: rect.x, y: rect.y, width: fit.width, height: fit.height},
+        }
+    }
+
+    pub fn get_desktop_geometry(ewmh: &ewmh::Connection, screen: i32) -> Result<(u32, u32), Error> {
+        let desktop_cookie = ewmh::get_desktop_geometry(ewmh, screen);
+        match desktop_cookie.get_reply() {
+            Ok(res) => Ok(res),
+            Err(e) => Err(Error::x11(ewmh, "get desktop geometry", e)),
+        }
+    }
+
+    pub fn get_work_area(connections: &XCBConnections, screen: i32) -> Result<Rect, Error> {
+        if let Some(area) = connections.cache.borrow().work_areas.get(&screen) {
+            return Ok(*area);
+        }
+        let ewmh = &connections.ewmh;
+        let area_cookie = ewmh::get_work_area(ewmh, screen);
+        let desktop_cookie = ewmh::get_current_desktop(ewmh, screen);
+        let areas = match area_cookie.get_reply() {
+            Ok(res) => res,
+            Err(e) => return Err(Error::x11(ewmh, "get work area", e)),
+        };
+        let idx = match desktop_cookie.get_reply() {
+            Ok(res) => res as usize,
+            Err(e) => return Err(Error::x11(ewmh, "get current desktop", e)),
+        };
+        let area = match areas.work_area().get(idx) {
+            Some(area) => Rect{
+                x: area.x() as i16,
+                y: area.y() as i16,
+                width: area.width() as u16,
+                height: area.height() as u16,
+            },
+            None => return Err(Error::window("couldn't find work area for screen")),
+        };
+        connections.cache.borrow_mut().work_areas.insert(screen, area);
+        Ok(area)
+    }
+
+    /// The monitors of a screen in the order Xinerama lists them, which matches
+    /// xrandr --listmonitors. Without Xinerama the whole screen is a single monitor.
+    pub fn get_monitors(connections: &XCBConnections, screen: i32) -> Result<Vec<Rect>, Error> {
+        if let Some(monitors) = connections.cache.borrow().monitors.get(&screen) {
+            return Ok(monitors.clone());
+        }
+        let base = connections.base();
+        let setup = base.get_setup();
+        let screen_rect = match setup.roots().nth(screen as usize) {
+            Some(root) => Rect{x: 0, y: 0, width: root.width_in_pixels(), height: root.height_in_pixels()},
+            None => return Err(Error::window("iterating through screens")),
+        };
+        // Requests for an extension the server doesn't have close the connection, and Xinerama
+        // only ever describes a single screen
+        let has_xinerama = base.get_extension_data(xinerama::id()).is_some_and(|data| data.present());
+        let mut monitors = Vec::new();
+        if has_xinerama && setup.roots().count() == 1 {
+            let reply = check_reply(base, "query xinerama screens", xinerama::query_screens(base).get_reply())?;
+            monitors = reply.screen_info()
+                .map(|info| Rect{x: info.x_org(), y: info.y_org(), width: info.width(), height: info.height()})
+                .collect();
+        }
+        if monitors.is_empty() {
+            monitors.push(screen_rect);
+        }
+        connections.cache.borrow_mut().monitors.insert(screen, monitors.clone());
+        Ok(monitors)
+    }
+
+    /// The part of the work area on the given monitor, None if there's no such monitor
+    pub fn get_monitor_work_area(connections: &XCBConnections, screen: i32, monitor: usize) -> Result<Option<Rect>, Error> {
+        let work_area = get_work_area(connections, screen)?;
+        let monitors = get_monitors(connections, screen)?;
+        Ok(monitors.get(monitor).and_then(|rect| rect.intersection(&work_area)))
+    }
+
+    /// How move_resize asks for a new window geometry
+    #[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum MoveMethod {
+        /// _NET_MOVERESIZE_WINDOW if the window manager lists it in _NET_SUPPORTED
+        Auto,
+        /// Always send _NET_MOVERESIZE_WINDOW client messages
+        Ewmh,
+        /// Configure the client window directly
+        Configure,
+    }
+
+    /// Whether the window manager lists the hint in _NET_SUPPORTED, which is only asked for
+    /// again after the window manager changes it
+    pub fn ewmh_supports(connections: &XCBConnections, screen: i32, atom: xproto::Atom) -> Result<bool, Error> {
+        if let Some(supported) = connections.cache.borrow().supported.get(&screen) {
+            return Ok(supported.contains(&atom));
+        }
+        let ewmh = &connections.ewmh;
+        let supported = match ewmh::get_supported(ewmh, screen).get_reply() {
+            Ok(reply) => reply.atoms().to_vec(),
+            Err(e) => match Error::x11(ewmh, "get supported hints", e) {
+                // No _NET_SUPPORTED at all, the window manager doesn't speak EWMH
+                Error::PropertyMissing(_) => Vec::new(),
+                error => return Err(error),
+            },
+        };
+        let supports = supported.contains(&atom);
+        connections.cache.borrow_mut().supported.insert(screen, supported);
+        Ok(supports)
+    }
+
+    /// Moves and resizes the window so that its visible geometry matches the arguments
+    pub fn move_resize(connections: &XCBConnections, window: xproto::Window, screen: i32,
+                       method: MoveMethod, decorations: Decorations, rect: Rect) -> Result<(), Error> {
+        let ewmh = &connections.ewmh;
+        let use_ewmh = match method {
+            MoveMethod::Auto => ewmh_supports(connections, screen, ewmh.MOVERESIZE_WINDOW())?,
+            MoveMethod::Ewmh => true,
+            MoveMethod::Configure => false,
+        };
+        match use_ewmh {
+            true => ewmh_move_resize(ewmh, window, screen, decorations, rect),
+            false => configure_move_resize(connections.base(), window, decorations, rect),
+        }
+    }
+
+    fn ewmh_move_resize(ewmh: &ewmh::Connection, window: xproto::Window, screen: i32, decorations: Decorations,
+                        rect: Rect) -> Result<(), Error> {
+        // StaticGravity makes the coordinates refer to the client window itself, so we don't
+        // depend on how the window manager interprets the window's own gravity
+        let client = decorations.client_geometry(rect);
+        let flags = ewmh::MOVE_RESIZE_WINDOW_X | ewmh::MOVE_RESIZE_WINDOW_Y
+            | ewmh::MOVE_RESIZE_WINDOW_WIDTH | ewmh::MOVE_RESIZE_WINDOW_HEIGHT;
+        let cookie = ewmh::request_move_resize_window(
+            ewmh,
+            screen,
+            window,
+            xproto::GRAVITY_STATIC,
+            ewmh::CLIENT_SOURCE_TYPE_OTHER, // we're a pager-like tool, not the application
+            flags,
+            client.x as i32 as u32,
+            client.y as i32 as u32,
+            client.width as u32,
+            client.height as u32,
+        );
+        match cookie.request_check() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::x11(ewmh, "moveresize window send message", e)),
+        }
+    }
+
+    fn configure_move_resize(base: &base::Connection, window: xproto::Window, decorations: Decorations,
+                             rect: Rect) -> Result<(), Error> {
+        let client = decorations.client_geometry(rect);
+        // With the default NorthWest gravity the window manager puts the outer corner of its
+        // frame at the requested position, so only the client-side margins need shifting
+        let x = clamp_coordinate(rect.x as i64 - decorations.client.left as i64);
+        let y = clamp_coordinate(rect.y as i64 - decorations.client.top as i64);
+        let value_list = [
+            (xproto::CONFIG_WINDOW_X as u16, x as u32),
+            (xproto::CONFIG_WINDOW_Y as u16, y as u32),
+            (xproto::CONFIG_WINDOW_WIDTH as u16, client.width as u32),
+            (xproto::CONFIG_WINDOW_HEIGHT as u16, client.height as u32),
+        ];
+        let cookie = xproto::configure_window(base, window, &value_list);
+        match cookie.request_check() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::x11(base, "move and resize window", e)),
+        }
+    }
+
+    pub fn ewmh_restore(ewmh: &ewmh::Connection, window: xproto::Window, screen: i32) -> Result<(), Error> {
+        let cookie = ewmh::request_change_wm_state(
             ewmh,
             screen,
             window,
@@ -297,11 +1962,11 @@ pub mod xcb {
         );
         match cookie.request_check() {
             Ok(_) => Ok(()),
-            Err(_) => Err(GenericError::new("maximize wm_state send message")),
+            Err(e) => Err(Error::x11(ewmh, "maximize wm_state send message", e)),
         }
     }
 
-    pub fn ewmh_maximize(ewmh: &ewmh::Connection, window: xproto::Window, screen: i32) -> Result<(), GenericError> {
+    pub fn ewmh_maximize(ewmh: &ewmh::Connection, window: xproto::Window, screen: i32) -> Result<(), Error> {
         let cookie = ewmh::request_change_wm_state(
             ewmh,
             screen,
@@ -313,36 +1978,892 @@ pub mod xcb {
         );
         match cookie.request_check() {
             Ok(_) => Ok(()),
-            Err(_) => Err(GenericError::new("maximize wm_state send message")),
+            Err(e) => Err(Error::x11(ewmh, "maximize wm_state send message", e)),
         }
     }
 
-    pub fn setup_connections() -> Result<XCBConnections, GenericError> {
-        let base_connection_res = base::Connection::connect(None);
-        let (base_connection, default_screen);
-        match base_connection_res {
-            Ok((conn, screen)) => {
-                base_connection = conn;
-                default_screen = screen;
-            },
-            _ => return Err(GenericError::new("XCB connection #1")),
+    pub fn ewmh_fullscreen(ewmh: &ewmh::Connection, window: xproto::Window, screen: i32, action: ewmh::StateAction) -> Result<(), Error> {
+        ewmh_change_state(ewmh, window, screen, action, ewmh.WM_STATE_FULLSCREEN())
+    }
+
+    // _NET_WM_STATE names accepted by the state command, without the prefix
+    fn wm_state_atoms(ewmh: &ewmh::Connection) -> [(&'static str, xproto::Atom); 12] {
+        [
+            ("modal", ewmh.WM_STATE_MODAL()),
+            ("sticky", ewmh.WM_STATE_STICKY()),
+            ("maximized_vert", ewmh.WM_STATE_MAXIMIZED_VERT()),
+            ("maximized_horz", ewmh.WM_STATE_MAXIMIZED_HORZ()),
+            ("shaded", ewmh.WM_STATE_SHADED()),
+            ("skip_taskbar", ewmh.WM_STATE_SKIP_TASKBAR()),
+            ("skip_pager", ewmh.WM_STATE_SKIP_PAGER()),
+            ("hidden", ewmh.WM_STATE_HIDDEN()),
+            ("fullscreen", ewmh.WM_STATE_FULLSCREEN()),
+            ("above", ewmh.WM_STATE_ABOVE()),
+            ("below", ewmh.WM_STATE_BELOW()),
+            ("demands_attention", ewmh.WM_STATE_DEMANDS_ATTENTION()),
+        ]
+    }
+
+    /// Looks up a state by its short name ("above") or full atom name ("_NET_WM_STATE_ABOVE")
+    pub fn get_wm_state_atom(connections: &XCBConnections, name: &str) -> Result<xproto::Atom, Error> {
+        // Atoms nobody has interned can't be a state the window manager knows, and interning
+        // them would leave a new atom on the server for every typo
+        if name.starts_with('_') {
+            let base = connections.base();
+            let atom_cookie = xproto::intern_atom(base, true, name);
+            return match check_reply(base, "intern atom", atom_cookie.get_reply())?.atom() {
+                xcb::NONE => Err(Error::invalid_command(&format!("unknown window state {}", name))),
+                atom => Ok(atom),
+            };
+        }
+        match wm_state_atoms(&connections.ewmh).iter().find(|(known, _)| *known == name) {
+            Some((_, atom)) => Ok(*atom),
+            None => Err(Error::invalid_command(&format!("unknown window state {}", name))),
+        }
+    }
+
+    pub fn ewmh_state_action(action: StateAction) -> ewmh::StateAction {
+        match action {
+            StateAction::Add => ewmh::STATE_ADD,
+            StateAction::Remove => ewmh::STATE_REMOVE,
+            StateAction::Toggle => ewmh::STATE_TOGGLE,
+        }
+    }
+
+    pub fn ewmh_change_state(ewmh: &ewmh::Connection, window: xproto::Window, screen: i32,
+                             action: ewmh::StateAction, state: xproto::Atom) -> Result<(), Error> {
+        let cookie = ewmh::request_change_wm_state(
+            ewmh,
+            screen,
+            window,
+            action,
+            state,
+            xcb::NONE,
+            ewmh::CLIENT_SOURCE_TYPE_NORMAL,
+        );
+        match cookie.request_check() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::x11(ewmh, "change wm_state send message", e)),
+        }
+    }
+
+    /// Asks the window manager to iconify the window (ICCCM 4.1.4)
+    pub fn icccm_iconify(connections: &XCBConnections, window: xproto::Window, screen: i32) -> Result<(), Error> {
+        let base = connections.base();
+        let change_state = connections.atom("WM_CHANGE_STATE")?;
+        let root = get_screen_root_window(base, screen)?;
+        let data = xproto::ClientMessageData::from_data32([icccm::XCB_ICCCM_WM_STATE_ICONIC as u32, 0, 0, 0, 0]);
+        let event = xproto::ClientMessageEvent::new(32, window, change_state, data);
+        let cookie = xproto::send_event_checked(
+            base,
+            false,
+            root,
+            xproto::EVENT_MASK_SUBSTRUCTURE_REDIRECT | xproto::EVENT_MASK_SUBSTRUCTURE_NOTIFY,
+            &event,
+        );
+        match cookie.request_check() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::x11(base, "iconify wm_change_state send message", e)),
+        }
+    }
+
+    /// Asks the window manager to activate the window, which also deiconifies it
+    pub fn ewmh_activate(ewmh: &ewmh::Connection, window: xproto::Window, screen: i32) -> Result<(), Error> {
+        let current = get_screen_active_window(ewmh, screen).unwrap_or(xcb::NONE);
+        let cookie = ewmh::request_change_active_window(
+            ewmh,
+            screen,
+            window,
+            ewmh::CLIENT_SOURCE_TYPE_OTHER,
+            xproto::TIME_CURRENT_TIME,
+            current,
+        );
+        match cookie.request_check() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::x11(ewmh, "active window send message", e)),
+        }
+    }
+
+    // Fields of _MOTIF_WM_HINTS are (flags, functions, decorations, input_mode, status)
+    const MOTIF_HINTS_LEN: usize = 5;
+    const MOTIF_HINTS_DECORATIONS: u32 = 1 << 1;
+    const MOTIF_DECOR_ALL: u32 = 1;
+
+    fn get_motif_hints(base: &base::Connection, window: xproto::Window, atom: xproto::Atom) -> Result<[u32; MOTIF_HINTS_LEN], Error> {
+        // Some clients set the type to INTEGER instead of _MOTIF_WM_HINTS
+        let values = get_property_values(base, window, atom, xproto::ATOM_ANY, MOTIF_HINTS_LEN as u32)?;
+        let mut hints = [0; MOTIF_HINTS_LEN];
+        if values.len() == MOTIF_HINTS_LEN {
+            hints.copy_from_slice(&values);
+        }
+        Ok(hints)
+    }
+
+    /// Whether the window asks for window manager decorations, which it does unless told otherwise
+    pub fn is_decorated(connections: &XCBConnections, window: xproto::Window) -> Result<bool, Error> {
+        let motif_wm_hints = connections.atom("_MOTIF_WM_HINTS")?;
+        let hints = get_motif_hints(connections.base(), window, motif_wm_hints)?;
+        Ok(hints[0] & MOTIF_HINTS_DECORATIONS == 0 || hints[2] != 0)
+    }
+
+    pub fn set_decorated(connections: &XCBConnections, window: xproto::Window, decorated: bool) -> Result<(), Error> {
+        let base = connections.base();
+        let motif_wm_hints = connections.atom("_MOTIF_WM_HINTS")?;
+        // Keep whatever functions and input mode the application asked for
+        let mut hints = get_motif_hints(base, window, motif_wm_hints)?;
+        hints[0] |= MOTIF_HINTS_DECORATIONS;
+        hints[2] = match decorated {
+            true => MOTIF_DECOR_ALL,
+            false => 0,
+        };
+        let cookie = xproto::change_property_checked(
+            base,
+            xproto::PROP_MODE_REPLACE as u8,
+            window,
+            motif_wm_hints,
+            motif_wm_hints,
+            32,
+            &hints,
+        );
+        match cookie.request_check() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::x11(base, "change motif wm hints", e)),
+        }
+    }
+
+    /// Polls the window's decorations until they differ from `old`, since window managers
+    /// publish new frame extents some time after the decoration hints change
+    pub fn wait_for_decorations(connections: &XCBConnections, window: xproto::Window,
+                                old: Decorations) -> Result<Decorations, Error> {
+        for _ in 0..DECORATION_POLL_ATTEMPTS {
+            let decorations = get_decorations(connections, window)?;
+            if decorations != old {
+                return Ok(decorations);
+            }
+            thread::sleep(Duration::from_millis(DECORATION_POLL_MS));
+        }
+        Ok(old)
+    }
+
+    /// A key combination grabbed on every root window
+    #[derive(Debug, Clone)]
+    pub struct GrabbedKey {
+        pub modifiers: u16,
+        pub keycode: xproto::Keycode,
+        pub command: Vec<String>,
+    }
+
+    // Caps Lock and Num Lock shouldn't stop a binding from firing
+    const IGNORED_MODIFIERS: [u16; 4] = [
+        0,
+        xproto::MOD_MASK_LOCK as u16,
+        xproto::MOD_MASK_2 as u16,
+        (xproto::MOD_MASK_LOCK | xproto::MOD_MASK_2) as u16,
+    ];
+
+    fn get_keycodes(base: &base::Connection, keysym: xproto::Keysym) -> Result<Vec<xproto::Keycode>, Error> {
+        let setup = base.get_setup();
+        let (min_keycode, max_keycode) = (setup.min_keycode(), setup.max_keycode());
+        let mapping_cookie = xproto::get_keyboard_mapping(base, min_keycode, max_keycode - min_keycode + 1);
+        let mapping = check_reply(base, "get keyboard mapping", mapping_cookie.get_reply())?;
+        let per_keycode = mapping.keysyms_per_keycode() as usize;
+        if per_keycode == 0 {
+            return Ok(Vec::new());
+        }
+        Ok(mapping.keysyms().chunks(per_keycode).enumerate()
+           .filter(|(_, keysyms)| keysyms.contains(&keysym))
+           .map(|(idx, _)| min_keycode + idx as u8)
+           .collect())
+    }
+
+    pub fn grab_keys(base: &base::Connection, bindings: &[KeyBinding]) -> Result<Vec<GrabbedKey>, Error> {
+        let mut grabbed = Vec::new();
+        for binding in bindings {
+            for keycode in get_keycodes(base, binding.keysym)? {
+                grabbed.push(GrabbedKey{modifiers: binding.modifiers, keycode, command: binding.command.clone()});
+            }
+        }
+        for screen in base.get_setup().roots() {
+            for key in grabbed.iter() {
+                for ignored in IGNORED_MODIFIERS.iter() {
+                    let cookie = xproto::grab_key_checked(base, true, screen.root(), key.modifiers | ignored, key.keycode,
+                                                          xproto::GRAB_MODE_ASYNC as u8, xproto::GRAB_MODE_ASYNC as u8);
+                    if let Err(e) = cookie.request_check() {
+                        return Err(Error::x11(base, &format!("grab key {}, is it bound elsewhere?", key.command.join(" ")), e));
+                    }
+                }
+            }
+        }
+        Ok(grabbed)
+    }
+
+    pub fn ungrab_keys(base: &base::Connection, grabbed: &[GrabbedKey]) {
+        for screen in base.get_setup().roots() {
+            for key in grabbed {
+                for ignored in IGNORED_MODIFIERS.iter() {
+                    xproto::ungrab_key(base, key.keycode, screen.root(), key.modifiers | ignored);
+                }
+            }
+        }
+        base.flush();
+    }
+
+    pub fn find_key_command(grabbed: &[GrabbedKey], keycode: xproto::Keycode, state: u16) -> Option<&[String]> {
+        let ignored = IGNORED_MODIFIERS[3];
+        grabbed.iter()
+            .find(|key| key.keycode == keycode && key.modifiers == state & !ignored)
+            .map(|key| key.command.as_slice())
+    }
+
+    const PRELOADED_ATOMS: [&str; 5] = ["_GTK_FRAME_EXTENTS", "_MOTIF_WM_HINTS", "UTF8_STRING", "WM_CHANGE_STATE", "WM_WINDOW_ROLE"];
+
+    pub fn setup_connections(display: &str) -> Result<XCBConnections, Error> {
+        let (base_connection, screen) = match base::Connection::connect(Some(display)) {
+            Ok(res) => res,
+            Err(e) => return Err(Error::connection("XCB connection", Some(e))),
+        };
+        let ewmh = match ewmh::Connection::connect(base_connection) {
+            Ok(ewmh) => ewmh,
+            Err(_) => return Err(Error::connection("XCB EWMH connection", None)),
+        };
+        let connections = XCBConnections{ewmh, screen, cache: RefCell::new(XCache::default())};
+        // Intern the atoms every command ends up needing in one round trip
+        let atom_cookies: Vec<_> = PRELOADED_ATOMS.iter()
+            .map(|name| xproto::intern_atom(connections.base(), false, name))
+            .collect();
+        for (name, atom_cookie) in PRELOADED_ATOMS.iter().zip(atom_cookies) {
+            let reply = check_reply(connections.base(), "intern atom", atom_cookie.get_reply())?;
+            connections.cache.borrow_mut().atoms.insert(name.to_string(), reply.atom());
+        }
+        Ok(connections)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::env;
+
+        // For the ignored tests that talk to a real server, run with --ignored
+        fn test_connections() -> XCBConnections {
+            let display = env::var("DISPLAY").expect("DISPLAY must name the X server to test against");
+            setup_connections(&display).unwrap()
+        }
+
+        fn create_test_window(connections: &XCBConnections) -> xproto::Window {
+            let base = connections.base();
+            let root = get_screen_root_window(base, connections.screen).unwrap();
+            let window = base.generate_id();
+            xproto::create_window(base, xcb::COPY_FROM_PARENT as u8, window, root, 0, 0, 1, 1, 0,
+                xproto::WINDOW_CLASS_INPUT_OUTPUT as u16, xcb::COPY_FROM_PARENT, &[]);
+            window
+        }
+
+        fn rect(x: i16, y: i16, width: u16, height: u16) -> Rect {
+            Rect{x, y, width, height}
+        }
+
+        fn extents(left: u32, right: u32, top: u32, bottom: u32) -> Extents {
+            Extents{left, right, top, bottom}
+        }
+
+        #[test]
+        fn frame_extents_only() {
+            // A title bar and thin borders drawn by the window manager
+            let decorations = Decorations{frame: extents(2, 2, 24, 2), client: Extents::default()};
+            assert_eq!(decorations.client_offset(), (2, 24));
+            assert_eq!(decorations.size_difference(), (4, 26));
+            let client = rect(102, 124, 800, 600);
+            let outer = rect(100, 100, 804, 626);
+            assert_eq!(decorations.outer_geometry(client), outer);
+            assert_eq!(decorations.client_geometry(outer), client);
+        }
+
+        #[test]
+        fn client_side_decorations_only() {
+            // _GTK_FRAME_EXTENTS: the client window is larger than what the user sees
+            let decorations = Decorations{frame: Extents::default(), client: extents(23, 23, 15, 31)};
+            assert_eq!(decorations.client_offset(), (-23, -15));
+            assert_eq!(decorations.size_difference(), (-46, -46));
+            let client = rect(77, 85, 846, 646);
+            let outer = rect(100, 100, 800, 600);
+            assert_eq!(decorations.outer_geometry(client), outer);
+            assert_eq!(decorations.client_geometry(outer), client);
+        }
+
+        #[test]
+        fn frame_and_client_side_decorations() {
+            let decorations = Decorations{frame: extents(1, 1, 20, 1), client: extents(10, 10, 10, 10)};
+            assert_eq!(decorations.client_offset(), (-9, 10));
+            assert_eq!(decorations.size_difference(), (-18, 1));
+            let outer = rect(0, 0, 960, 1080);
+            let client = decorations.client_geometry(outer);
+            assert_eq!(client, rect(-9, 10, 978, 1079));
+            assert_eq!(decorations.outer_geometry(client), outer);
+        }
+
+        #[test]
+        fn client_geometry_never_collapses() {
+            let decorations = Decorations{frame: extents(5, 5, 30, 5), client: Extents::default()};
+            let client = decorations.client_geometry(rect(0, 0, 4, 20));
+            assert_eq!((client.width, client.height), (1, 1));
+        }
+
+        #[test]
+        fn frame_tree_fallback() {
+            // A reparenting window manager that doesn't publish _NET_FRAME_EXTENTS
+            let client = rect(105, 125, 800, 600);
+            let frame = rect(100, 100, 810, 630);
+            assert_eq!(frame_extents(client, frame, 0), extents(5, 5, 25, 5));
+            // The frame's own X border is outside of its geometry
+            assert_eq!(frame_extents(client, frame, 2), extents(7, 7, 27, 7));
+            let decorations = Decorations{frame: frame_extents(client, frame, 0), client: Extents::default()};
+            assert_eq!(decorations.outer_geometry(client), frame);
+        }
+
+        #[test]
+        fn frame_tree_fallback_ignores_clients_outside_their_frame() {
+            let client = rect(90, 100, 800, 600);
+            let frame = rect(100, 100, 780, 600);
+            assert_eq!(frame_extents(client, frame, 0), extents(0, 0, 0, 0));
+        }
+
+        #[test]
+        fn non_reparenting_border_only() {
+            // Without a frame window only the client's X border surrounds it
+            let decorations = Decorations{frame: Extents::uniform(3), client: Extents::default()};
+            let client = rect(50, 50, 640, 480);
+            assert_eq!(decorations.outer_geometry(client), rect(47, 47, 646, 486));
+            assert_eq!(decorations.client_geometry(rect(47, 47, 646, 486)), client);
         }
-        let ewmh_connection_res = ewmh::Connection::connect(base_connection);
-        let ewmh_connection;
-        match ewmh_connection_res {
-            Ok(conn) => ewmh_connection = conn,
-            _ => return Err(GenericError::new("XCB EWMH connection")),
+
+        #[test]
+        fn monitor_work_area_is_the_overlap() {
+            // A panel along the top of the left monitor
+            let work_area = rect(0, 30, 3840, 1050);
+            let left = rect(0, 0, 1920, 1080);
+            let right = rect(1920, 0, 1920, 1080);
+            assert_eq!(left.intersection(&work_area), Some(rect(0, 30, 1920, 1050)));
+            assert_eq!(right.intersection(&work_area), Some(rect(1920, 30, 1920, 1050)));
+            assert_eq!(left.intersection(&right), None);
+        }
+
+        #[test]
+        fn extents_from_cardinals_keep_the_ewmh_order() {
+            assert_eq!(Extents::from_cardinals(&[1, 2, 3, 4]), Some(extents(1, 2, 3, 4)));
+        }
+
+        #[test]
+        fn increments_count_from_the_base_size() {
+            let hints = SizeHints{base_size: Some((10, 20)), increment: Some((7, 5)), ..SizeHints::default()};
+            assert_eq!(hints.constrain(100, 100), (94, 100));
+            // Without a base size the minimum size is where the increments start
+            let hints = SizeHints{min_size: Some((3, 4)), increment: Some((10, 10)), ..SizeHints::default()};
+            assert_eq!(hints.constrain(100, 100), (93, 94));
+        }
+
+        #[test]
+        fn base_size_stands_in_for_the_minimum() {
+            let hints = SizeHints{base_size: Some((50, 60)), ..SizeHints::default()};
+            assert_eq!(hints.constrain(20, 30), (50, 60));
+            assert_eq!(hints.constrain(200, 300), (200, 300));
+        }
+
+        #[test]
+        fn minimum_size_wins() {
+            let hints = SizeHints{min_size: Some((300, 300)), max_size: Some((200, 200)), ..SizeHints::default()};
+            assert_eq!(hints.constrain(1000, 1000), (300, 300));
+            let hints = SizeHints{min_size: Some((400, 100)), aspect: Some(((1, 1), (1, 1))), ..SizeHints::default()};
+            assert_eq!(hints.constrain(200, 200), (400, 200));
+        }
+
+        #[test]
+        fn aspect_clamps_both_ways() {
+            // Between square and twice as wide as tall
+            let hints = SizeHints{aspect: Some(((1, 1), (2, 1))), ..SizeHints::default()};
+            assert_eq!(hints.constrain(100, 300), (100, 100));
+            assert_eq!(hints.constrain(500, 100), (200, 100));
+            assert_eq!(hints.constrain(150, 100), (150, 100));
+            // Only the part above the base size has to keep the aspect
+            let hints = SizeHints{base_size: Some((10, 10)), aspect: Some(((1, 1), (1, 1))), ..SizeHints::default()};
+            assert_eq!(hints.constrain(110, 210), (110, 110));
+        }
+
+        #[test]
+        fn pre_icccm_size_hints() {
+            let flags = icccm::XCB_ICCCM_SIZE_HINT_P_MIN_SIZE | icccm::XCB_ICCCM_SIZE_HINT_P_ASPECT
+                | icccm::XCB_ICCCM_SIZE_HINT_BASE_SIZE | icccm::XCB_ICCCM_SIZE_HINT_P_WIN_GRAVITY;
+            let mut values = vec![flags, 0, 0, 0, 0, 100, 50, 0, 0, 0, 0, 1, 2, 3, 4];
+            // The base size and gravity flags are set, but a 15 field property has no room for them
+            assert_eq!(SizeHints::from_property(&values), SizeHints{
+                min_size: Some((100, 50)),
+                aspect: Some(((1, 2), (3, 4))),
+                ..SizeHints::default()
+            });
+            values.extend_from_slice(&[80, 40, 10]);
+            let hints = SizeHints::from_property(&values);
+            assert_eq!((hints.base_size, hints.gravity), (Some((80, 40)), Some(10)));
+            assert_eq!(SizeHints::from_property(&values[..14]), SizeHints::default());
+        }
+
+        #[test]
+        fn implausible_extents_are_rejected() {
+            assert_eq!(Extents::from_cardinals(&[0, 0, 0, MAX_EXTENT]), Some(extents(0, 0, 0, MAX_EXTENT)));
+            assert_eq!(Extents::from_cardinals(&[0, u32::MAX, 0, 0]), None);
+            assert_eq!(Extents::from_cardinals(&[MAX_EXTENT + 1, 0, 0, 0]), None);
         }
 
-        // For now we do all of this again to re-establish base_connection
-        let base_connection_res = base::Connection::connect(None);
-        let base_connection;
-        match base_connection_res {
-            Ok((conn, _screen)) => {
-                base_connection = conn;
+        #[test]
+        fn huge_extents_saturate() {
+            // Decorations are public, so nothing guarantees they went through from_cardinals
+            let huge = extents(u32::MAX, u32::MAX, u32::MAX, u32::MAX);
+            let frame_only = Decorations{frame: huge, client: Extents::default()};
+            assert_eq!(frame_only.client_offset(), (i16::MAX, i16::MAX));
+            assert_eq!(frame_only.size_difference(), (u16::MAX as i32, u16::MAX as i32));
+            assert_eq!(frame_only.outer_geometry(rect(0, 0, 800, 600)), rect(-i16::MAX, -i16::MAX, u16::MAX, u16::MAX));
+            assert_eq!(frame_only.client_geometry(rect(i16::MAX, 0, 800, 600)), rect(i16::MAX, i16::MAX, 1, 1));
+            let client_only = Decorations{frame: Extents::default(), client: huge};
+            assert_eq!(client_only.client_offset(), (i16::MIN, i16::MIN));
+            assert_eq!(client_only.outer_geometry(rect(i16::MIN, 0, 800, 600)), rect(0, i16::MAX, 1, 1));
+        }
+
+        // Both interfaces write into the same request stream, so a reply always reflects every
+        // request sent before it, whichever interface sent them
+        #[test]
+        #[ignore = "needs an X server"]
+        fn requests_are_processed_in_the_order_they_are_sent() {
+            let connections = test_connections();
+            let base = connections.base();
+            let window = create_test_window(&connections);
+
+            ewmh::set_wm_name(&connections.ewmh, window, "ordering");
+            let name = xproto::get_property(base, false, window, connections.ewmh.WM_NAME(),
+                connections.atom("UTF8_STRING").unwrap(), 0, 64).get_reply().unwrap();
+            assert_eq!(name.value::<u8>(), b"ordering");
+
+            xproto::change_property(base, xproto::PROP_MODE_REPLACE as u8, window,
+                connections.ewmh.WM_PID(), xproto::ATOM_CARDINAL, 32, &[42u32]);
+            assert_eq!(ewmh::get_wm_pid(&connections.ewmh, window).get_reply().unwrap(), 42);
+
+            // Replies collected out of order still belong to their own requests
+            let pid_cookie = ewmh::get_wm_pid(&connections.ewmh, window);
+            let name_cookie = ewmh::get_wm_name(&connections.ewmh, window);
+            assert_eq!(name_cookie.get_reply().unwrap().string(), "ordering");
+            assert_eq!(pid_cookie.get_reply().unwrap(), 42);
+
+            xproto::destroy_window(base, window);
+            base.flush();
+        }
+    }
+}
+
+pub mod config {
+    use crate::errors::Error;
+    use crate::xcb::{MoveMethod, SizeHintPolicy, WINDOW_TYPES};
+    use crate::socket::SOCKET_BUFFER_LEN;
+    use crate::commands::*;
+    use crate::data::{binding_request, CYCLE_RATIO};
+    use serde::Deserialize;
+    use std::collections::HashMap;
+    use std::ffi::{CString, OsString};
+    use std::fs;
+    use std::io;
+    use std::mem;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::os::unix::io::RawFd;
+    use std::path::{Path, PathBuf};
+    use xcb::xproto;
+
+    const CONFIG_DIR: &str = "sss";
+    const CONFIG_FILE: &str = "config.toml";
+    const INOTIFY_BUFFER_LEN: usize = 4096;
+
+    #[derive(Deserialize, Debug, Clone, PartialEq)]
+    #[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+    pub struct Config {
+        /// Overrides the default socket location
+        pub socket_path: Option<PathBuf>,
+        /// Largest datagram the server and client will read
+        pub buffer_len: usize,
+        pub size_hints: SizeHintPolicy,
+        pub move_method: MoveMethod,
+        /// Window types the server refuses to manage
+        pub exclude_types: Vec<String>,
+        /// Fractions of the work area a split covers, splitting to the same side again
+        /// moves on to the next one
+        pub ratios: Vec<f64>,
+        pub gaps: Gaps,
+        /// Key combinations such as "super+Left" mapped to commands such as "splitleft"
+        pub keybindings: HashMap<String, String>,
+        /// Commands run on matching windows when they first appear
+        pub rules: Vec<Rule>,
+        pub log: LogConfig,
+    }
+
+    /// Matches a new window on every given field, the class matches either name in WM_CLASS
+    /// and the title matches any part of the window title
+    #[derive(Deserialize, Debug, Clone, PartialEq)]
+    #[serde(deny_unknown_fields)]
+    pub struct Rule {
+        pub class: Option<String>,
+        pub title: Option<String>,
+        pub role: Option<String>,
+        /// Split on this monitor, counting from 0 in the order of xrandr --listmonitors
+        pub monitor: Option<usize>,
+        pub command: String,
+    }
+
+    impl Rule {
+        pub fn matches(&self, class: &[String], title: &str, role: &str) -> bool {
+            self.class.as_ref().is_none_or(|name| class.contains(name))
+                && self.title.as_ref().is_none_or(|part| title.contains(part.as_str()))
+                && self.role.as_ref().is_none_or(|name| name == role)
+        }
+
+        pub fn command_words(&self) -> Vec<String> {
+            self.command.split_whitespace().map(String::from).collect()
+        }
+    }
+
+    // Anything larger leaves no room for the window on any real screen, and keeps the gap
+    // arithmetic on 16 bit coordinates from overflowing
+    const MAX_GAP: u16 = 1000;
+
+    /// Spacing around split windows in pixels. Maximized windows are left to the window manager.
+    #[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct Gaps {
+        /// Between windows and the edges of the work area
+        pub outer: u16,
+        /// Between the two halves of a split
+        pub inner: u16,
+    }
+
+    #[derive(Deserialize, Debug, Clone, PartialEq)]
+    #[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+    pub struct LogConfig {
+        /// Append the server's output to this file instead of stderr
+        pub file: Option<PathBuf>,
+        /// Size in bytes after which the log is moved to <file>.1, 0 never rotates
+        pub max_size: u64,
+        /// Log every handled command
+        pub verbose: bool,
+    }
+
+    impl Default for LogConfig {
+        fn default() -> LogConfig {
+            LogConfig{file: None, max_size: 1024 * 1024, verbose: false}
+        }
+    }
+
+    impl Default for Config {
+        fn default() -> Config {
+            Config{
+                socket_path: None,
+                buffer_len: SOCKET_BUFFER_LEN,
+                size_hints: SizeHintPolicy::Center,
+                move_method: MoveMethod::Auto,
+                exclude_types: vec!["desktop", "dock", "notification", "splash"].into_iter().map(String::from).collect(),
+                ratios: vec![0.5],
+                gaps: Gaps::default(),
+                keybindings: HashMap::new(),
+                rules: Vec::new(),
+                log: LogConfig::default(),
+            }
+        }
+    }
+
+    /// A parsed key binding, the command is split into words like on the command line
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct KeyBinding {
+        pub modifiers: u16,
+        pub keysym: u32,
+        pub command: Vec<String>,
+    }
+
+    pub fn get_config_file() -> Result<PathBuf, Error> {
+        match dirs::config_dir() {
+            Some(mut configpath) => {
+                configpath.push(CONFIG_DIR);
+                configpath.push(CONFIG_FILE);
+                Ok(configpath)
             },
-            _ => return Err(GenericError::new("XCB connection #2")),
+            None => Err(Error::config("getting config directory")),
+        }
+    }
+
+    impl Config {
+        /// Reads and validates the config file, a missing file gives the defaults
+        pub fn load(path: &Path) -> Result<Config, Error> {
+            let contents = match fs::read_to_string(path) {
+                Ok(res) => res,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+                Err(e) => return Err(Error::io("read config file", e)),
+            };
+            let config: Config = match toml::from_str(&contents) {
+                Ok(res) => res,
+                Err(e) => return Err(Error::config(&format!("parse config file: {}", e))),
+            };
+            config.validate()?;
+            Ok(config)
+        }
+
+        fn validate(&self) -> Result<(), Error> {
+            if self.buffer_len == 0 {
+                return Err(Error::config("buffer-len must be positive"));
+            }
+            if let Some(excluded) = self.exclude_types.iter().find(|name| !WINDOW_TYPES.contains(&name.as_str())) {
+                return Err(Error::config(&format!("unknown window type {}", excluded)));
+            }
+            if self.ratios.is_empty() || self.ratios.iter().any(|ratio| *ratio <= 0.0 || *ratio >= 1.0) {
+                return Err(Error::config("ratios must be between 0 and 1"));
+            }
+            if self.gaps.outer > MAX_GAP || self.gaps.inner > MAX_GAP {
+                return Err(Error::config(&format!("gaps can't be larger than {} pixels", MAX_GAP)));
+            }
+            self.key_bindings()?;
+            self.validate_rules()?;
+            Ok(())
+        }
+
+        /// Split ratio for the n-th consecutive split to the same side
+        pub fn ratio(&self, idx: usize) -> f64 {
+            self.ratios[idx % self.ratios.len()]
+        }
+
+        pub fn key_bindings(&self) -> Result<Vec<KeyBinding>, Error> {
+            let mut bindings = Vec::new();
+            for (combo, command) in self.keybindings.iter() {
+                let (modifiers, keysym) = parse_key_combo(combo)?;
+                let command: Vec<String> = command.split_whitespace().map(String::from).collect();
+                match command.first() {
+                    Some(name) if WINDOW_COMMANDS.contains(&name.as_str()) || name == UNMINIMIZE || name == RELOAD => (),
+                    _ => return Err(Error::config(&format!("invalid command bound to {}", combo))),
+                }
+                if let Err(e) = self.validate_command(&command) {
+                    return Err(Error::config(&format!("command bound to {}: {}", combo, e)));
+                }
+                bindings.push(KeyBinding{modifiers, keysym, command});
+            }
+            Ok(bindings)
+        }
+
+        fn validate_rules(&self) -> Result<(), Error> {
+            for rule in self.rules.iter() {
+                if rule.class.is_none() && rule.title.is_none() && rule.role.is_none() {
+                    return Err(Error::config(&format!("rule for {} matches every window", rule.command)));
+                }
+                match rule.command_words().first() {
+                    Some(name) if WINDOW_COMMANDS.contains(&name.as_str()) => (),
+                    _ => return Err(Error::config(&format!("invalid rule command {}", rule.command))),
+                }
+                if let Err(e) = self.validate_command(&rule.command_words()) {
+                    return Err(Error::config(&format!("rule command {}: {}", rule.command, e)));
+                }
+            }
+            Ok(())
+        }
+
+        // Bindings and rules are parsed like the server will run them, so mistakes in their
+        // arguments show up when the config is loaded
+        fn validate_command(&self, words: &[String]) -> Result<(), Error> {
+            let ratio = binding_request(words)?.arguments.ratio;
+            if ratio != CYCLE_RATIO && ratio as usize >= self.ratios.len() {
+                return Err(Error::invalid_command(&format!("no split ratio {}, {} are configured", ratio, self.ratios.len())));
+            }
+            Ok(())
+        }
+    }
+
+    // Commands that act on a single window
+    const WINDOW_COMMANDS: [&str; 11] = [
+        RESTORE, SPLITLEFT, SPLITRIGHT, MAXIMIZE, FULLSCREEN, MINIMIZE, DECORATE, UNDECORATE,
+        TOGGLE_DECORATIONS, STATE, SAVE,
+    ];
+
+    // "super+shift+Left" becomes a modifier mask and a keysym
+    fn parse_key_combo(combo: &str) -> Result<(u16, u32), Error> {
+        let mut parts: Vec<&str> = combo.split('+').map(str::trim).collect();
+        let key = parts.pop().unwrap_or("");
+        let mut modifiers = 0;
+        for modifier in parts {
+            modifiers |= match modifier.to_lowercase().as_str() {
+                "shift" => xproto::MOD_MASK_SHIFT,
+                "control" | "ctrl" => xproto::MOD_MASK_CONTROL,
+                "mod1" | "alt" => xproto::MOD_MASK_1,
+                "mod3" => xproto::MOD_MASK_3,
+                "mod4" | "super" => xproto::MOD_MASK_4,
+                "mod5" => xproto::MOD_MASK_5,
+                _ => return Err(Error::config(&format!("unknown modifier {} in {}", modifier, combo))),
+            };
+        }
+        match keysym_from_name(key) {
+            Some(keysym) => Ok((modifiers as u16, keysym)),
+            None => Err(Error::config(&format!("unknown key {} in {}", key, combo))),
+        }
+    }
+
+    // Only the keys worth binding, anything else can be given as a hexadecimal keysym
+    fn keysym_from_name(name: &str) -> Option<u32> {
+        let mut chars = name.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            if c.is_ascii_alphanumeric() {
+                return Some(c.to_ascii_lowercase() as u32);
+            }
+        }
+        if let Some(hex) = name.strip_prefix("0x") {
+            return u32::from_str_radix(hex, 16).ok();
+        }
+        if let Some(num) = name.strip_prefix('F') {
+            if let Ok(n @ 1..=12) = num.parse::<u32>() {
+                return Some(0xffbe + n - 1);
+            }
+        }
+        match name {
+            "space" => Some(0x0020),
+            "BackSpace" => Some(0xff08),
+            "Tab" => Some(0xff09),
+            "Return" => Some(0xff0d),
+            "Escape" => Some(0xff1b),
+            "Home" => Some(0xff50),
+            "Left" => Some(0xff51),
+            "Up" => Some(0xff52),
+            "Right" => Some(0xff53),
+            "Down" => Some(0xff54),
+            "Prior" | "Page_Up" => Some(0xff55),
+            "Next" | "Page_Down" => Some(0xff56),
+            "End" => Some(0xff57),
+            "Insert" => Some(0xff63),
+            "Delete" => Some(0xffff),
+            _ => None,
+        }
+    }
+
+    /// Watches the config file through inotify. The directory is watched rather than the
+    /// file since editors usually save by replacing the file.
+    pub struct ConfigWatcher {
+        fd: RawFd,
+        file_name: OsString,
+    }
+
+    impl ConfigWatcher {
+        pub fn new(path: &Path) -> Result<ConfigWatcher, Error> {
+            let (dir, file_name) = match (path.parent(), path.file_name()) {
+                (Some(dir), Some(file_name)) => (dir, file_name.to_os_string()),
+                _ => return Err(Error::config("config path has no directory")),
+            };
+            let dir = match CString::new(dir.as_os_str().as_bytes()) {
+                Ok(res) => res,
+                Err(_) => return Err(Error::config("config directory contains nul byte")),
+            };
+            let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(Error::last_os_error("inotify init"));
+            }
+            let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE | libc::IN_DELETE;
+            if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) } < 0 {
+                unsafe { libc::close(fd) };
+                return Err(Error::last_os_error("watch config directory"));
+            }
+            Ok(ConfigWatcher{fd, file_name})
+        }
+
+        pub fn fd(&self) -> RawFd {
+            self.fd
+        }
+
+        /// Drains pending events and tells whether any of them touched the config file
+        pub fn changed(&self) -> bool {
+            let mut changed = false;
+            let mut buf = vec![0u8; INOTIFY_BUFFER_LEN];
+            loop {
+                let size = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+                if size <= 0 {
+                    return changed;
+                }
+                let mut offset = 0;
+                let header_len = mem::size_of::<libc::inotify_event>();
+                while offset + header_len <= size as usize {
+                    let event = unsafe { (buf.as_ptr().add(offset) as *const libc::inotify_event).read_unaligned() };
+                    let name_start = offset + header_len;
+                    let name_end = (name_start + event.len as usize).min(size as usize);
+                    let name: Vec<u8> = buf[name_start..name_end].iter().copied().take_while(|b| *b != 0).collect();
+                    if OsString::from_vec(name) == self.file_name {
+                        changed = true;
+                    }
+                    offset = name_end;
+                }
+            }
+        }
+    }
+
+    impl Drop for ConfigWatcher {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}
+
+pub mod signal {
+    use crate::errors::Error;
+    use std::mem;
+    use std::os::unix::io::RawFd;
+    use std::ptr;
+
+    /// Delivers signals through a file descriptor so they can be polled with everything else.
+    /// The signals are blocked for as long as this exists.
+    pub struct SignalFd {
+        fd: RawFd,
+        mask: libc::sigset_t,
+    }
+
+    impl SignalFd {
+        pub fn new(signals: &[libc::c_int]) -> Result<SignalFd, Error> {
+            let mut mask: libc::sigset_t = unsafe { mem::zeroed() };
+            unsafe {
+                libc::sigemptyset(&mut mask);
+                for signal in signals {
+                    libc::sigaddset(&mut mask, *signal);
+                }
+            }
+            // Blocked signals stay pending for the fd instead of killing the process
+            if unsafe { libc::sigprocmask(libc::SIG_BLOCK, &mask, ptr::null_mut()) } < 0 {
+                return Err(Error::last_os_error("block signals"));
+            }
+            let fd = unsafe { libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) };
+            if fd < 0 {
+                unsafe { libc::sigprocmask(libc::SIG_UNBLOCK, &mask, ptr::null_mut()) };
+                return Err(Error::last_os_error("create signalfd"));
+            }
+            Ok(SignalFd{fd, mask})
+        }
+
+        pub fn fd(&self) -> RawFd {
+            self.fd
+        }
+
+        /// Drains the signals received since the last call
+        pub fn pending(&self) -> Vec<libc::c_int> {
+            let mut signals = Vec::new();
+            loop {
+                let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+                let size = unsafe {
+                    libc::read(self.fd, &mut info as *mut libc::signalfd_siginfo as *mut libc::c_void,
+                               mem::size_of::<libc::signalfd_siginfo>())
+                };
+                if size != mem::size_of::<libc::signalfd_siginfo>() as isize {
+                    return signals;
+                }
+                signals.push(info.ssi_signo as libc::c_int);
+            }
+        }
+    }
+
+    impl Drop for SignalFd {
+        fn drop(&mut self) {
+            unsafe {
+                libc::close(self.fd);
+                libc::sigprocmask(libc::SIG_UNBLOCK, &self.mask, ptr::null_mut());
+            }
         }
-        Ok(XCBConnections{base: base_connection, ewmh: ewmh_connection, screen: default_screen})
     }
 }
@@ -1,195 +1,1149 @@
 use stateful_split_screen::xcb::*;
 use stateful_split_screen::socket::*;
-use stateful_split_screen::errors::GenericError;
+use stateful_split_screen::errors::Error;
 use stateful_split_screen::commands::*;
 use stateful_split_screen::data::*;
+use stateful_split_screen::config::*;
+use stateful_split_screen::signal::SignalFd;
+use xcb_util::ewmh;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use clap::{App, Arg};
 
-const SOCKET_BUFFER_LEN: usize = 1024;
-
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy, Serialize, Deserialize)]
 enum State {
     Windowed,
     SplitLeft,
     SplitRight,
     Maximized,
+    Fullscreen,
+    Minimized,
 }
 
-#[derive(PartialEq, Clone)]
-struct Dimensions {
-    x: i16,
-    y: i16,
-    width: u16,
-    height: u16,
+impl State {
+    // The command that puts a window back into this state
+    fn command(&self) -> Command {
+        match self {
+            State::Windowed => Command::Restore,
+            State::SplitLeft => Command::SplitLeft,
+            State::SplitRight => Command::SplitRight,
+            State::Maximized => Command::Maximize,
+            State::Fullscreen => Command::Fullscreen,
+            State::Minimized => Command::Minimize,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            State::Windowed => "windowed",
+            State::SplitLeft => SPLITLEFT,
+            State::SplitRight => SPLITRIGHT,
+            State::Maximized => "maximized",
+            State::Fullscreen => FULLSCREEN,
+            State::Minimized => "minimized",
+        }
+    }
 }
 
+#[derive(Serialize, Deserialize)]
 struct Properties {
     state: State,
-    dimensions: Dimensions,
+    dimensions: Rect,
+    // What the window returns to when leaving fullscreen
+    previous: State,
+    // What the window returns to when unminimized
+    before_minimize: State,
+    // Index into the configured split ratios
+    ratio: usize,
+    // The monitor splits cover, or ALL_MONITORS
+    monitor: i32,
+}
+
+impl Properties {
+    fn windowed(dimensions: Rect) -> Properties {
+        Properties{
+            state: State::Windowed,
+            dimensions,
+            previous: State::Windowed,
+            before_minimize: State::Windowed,
+            ratio: 0,
+            monitor: ALL_MONITORS,
+        }
+    }
 }
 
 fn conditionally_store_dimensions(
     active_window: xcb::xproto::Window,
     window_properties: &mut HashMap<xcb::xproto::Window, Properties>,
-    current_dimensions: Dimensions,
-    correct_dimensions: Dimensions,
+    current_dimensions: Rect,
+    correct_dimensions: Rect,
     state: State
 ) {
     if window_properties.get(&active_window).is_some()
         && window_properties.get(&active_window).unwrap().state == state
         && current_dimensions != correct_dimensions
     {
-        let prop = Properties{state: State::Windowed, dimensions: current_dimensions};
+        let prop = Properties::windowed(current_dimensions);
         window_properties.insert(active_window, prop);
     }
 }
 
+// Commands that don't change the geometry can be applied to fullscreen windows
+fn leaves_fullscreen(command: Command) -> bool {
+    !matches!(command, Command::Save | Command::Minimize | Command::Decorate | Command::Undecorate | Command::ToggleDecorations)
+}
+
+// The part of the work area a split covers, the inner gap is shared by both halves
+fn split_rectangle(work_area: Rect, state: State, ratio: f64, gaps: &Gaps) -> Rect {
+    let (area_x, area_y) = (work_area.x.saturating_add(gaps.outer as i16), work_area.y.saturating_add(gaps.outer as i16));
+    let area_width = work_area.width.saturating_sub(gaps.outer.saturating_mul(2));
+    let area_height = work_area.height.saturating_sub(gaps.outer.saturating_mul(2));
+    let width = ((area_width as f64 * ratio) as u16).saturating_sub(gaps.inner / 2);
+    match state {
+        State::SplitRight => Rect{x: area_x + (area_width - width) as i16, y: area_y, width, height: area_height},
+        _ => Rect{x: area_x, y: area_y, width, height: area_height},
+    }
+}
+
+fn fit_split(config: &Config, info: &WindowInfo, work_area: Rect, state: State, ratio: usize) -> Rect {
+    let rect = split_rectangle(work_area, state, config.ratio(ratio), &config.gaps);
+    fit_size_hints(info.decorations, &info.size_hints, config.size_hints, rect)
+}
+
+// The area splits divide, None when the monitor is gone
+fn get_split_area(connections: &XCBConnections, screen: i32, monitor: i32) -> Result<Option<Rect>, Error> {
+    match monitor {
+        ALL_MONITORS => get_work_area(connections, screen).map(Some),
+        monitor if monitor < 0 => Ok(None),
+        monitor => get_monitor_work_area(connections, screen, monitor as usize),
+    }
+}
+
+// Puts the window where its split state says it belongs
+fn apply_split(connections: &XCBConnections, config: &Config, info: &WindowInfo, prop: &Properties) -> Result<(), Error> {
+    let split_area = match get_split_area(connections, info.screen, prop.monitor)? {
+        Some(area) => area,
+        None => get_work_area(connections, info.screen)?,
+    };
+    let rect = fit_split(config, info, split_area, prop.state, prop.ratio);
+    move_resize(connections, info.window, info.screen, config.move_method, info.decorations, rect)
+}
+
 fn do_single_command(
     connections: &XCBConnections,
     window_properties: &mut HashMap<xcb::xproto::Window, Properties>,
-    message: Message,
-) -> Result<(), GenericError> {
-    if let None = message.get(COMMAND) {
-        return Err(GenericError::new("command not found in message"));
+    minimized: &mut Vec<xcb::xproto::Window>,
+    request: Request,
+    config: &Config,
+) -> Result<(), Error> {
+    // Minimized windows aren't active, so this one works off our own bookkeeping
+    if request.command == Command::Unminimize {
+        return unminimize(connections, window_properties, minimized, config);
     }
-    let base = &connections.base;
+    let window = request.target.window;
+    if window != 0 {
+        let screen = get_window_screen(connections.base(), window)?;
+        return do_window_command(connections, window_properties, minimized, window, screen, request, config);
+    }
+    let (active_window, screen) = get_active_window(connections.base(), &connections.ewmh)?;
+    // An active window can't be minimized, so it was restored behind our back
+    if let Some(prop) = window_properties.get_mut(&active_window) {
+        if prop.state == State::Minimized {
+            prop.state = prop.before_minimize;
+            minimized.retain(|window| *window != active_window);
+        }
+    }
+    do_window_command(connections, window_properties, minimized, active_window, screen, request, config)
+}
+
+fn unminimize(
+    connections: &XCBConnections,
+    window_properties: &mut HashMap<xcb::xproto::Window, Properties>,
+    minimized: &mut Vec<xcb::xproto::Window>,
+    config: &Config,
+) -> Result<(), Error> {
+    let base = connections.base();
     let ewmh = &connections.ewmh;
-    let _default_screen = connections.screen;
-    let (active_window, screen) = get_active_window(base, ewmh)?;
-    let (window_x, window_y, window_width, window_height) = get_geometry(base, ewmh, active_window)?;
-    let (work_x, work_y, work_width, work_height) = get_work_area(ewmh, screen)?;
-    let half_width = work_width / 2;
+    while let Some(window) = minimized.pop() {
+        // Windows closed while minimized no longer have a screen
+        let screen = match get_window_screen(base, window) {
+            Ok(screen) => screen,
+            Err(_) => {
+                window_properties.remove(&window);
+                continue;
+            },
+        };
+        // Windows restored behind our back are already where they belong, try the next one
+        let prop = match window_properties.get_mut(&window) {
+            Some(prop) if prop.state == State::Minimized => prop,
+            _ => continue,
+        };
+        ewmh_activate(ewmh, window, screen)?;
+        prop.state = prop.before_minimize;
+        // Window managers keep maximized and fullscreen states while iconified, but splits
+        // are only geometry so we put the window back ourselves
+        if prop.state == State::SplitLeft || prop.state == State::SplitRight {
+            let info = get_window_info(connections, window, screen)?;
+            return apply_split(connections, config, &info, prop);
+        }
+        return Ok(());
+    }
+    Err(Error::window("no minimized window to restore"))
+}
+
+fn do_window_command(
+    connections: &XCBConnections,
+    window_properties: &mut HashMap<xcb::xproto::Window, Properties>,
+    minimized: &mut Vec<xcb::xproto::Window>,
+    active_window: xcb::xproto::Window,
+    screen: i32,
+    request: Request,
+    config: &Config,
+) -> Result<(), Error> {
+    let ewmh = &connections.ewmh;
+    // Everything below works off this one round trip
+    let mut info = get_window_info(connections, active_window, screen)?;
+    if config.exclude_types.iter().any(|excluded| excluded == info.window_type) {
+        return Err(Error::window(&format!("{} windows are excluded from management", info.window_type)));
+    }
+    // Generic state changes don't touch the geometry, so the window manager handles the rest
+    if request.command == Command::State {
+        let arguments = &request.arguments;
+        let state = get_wm_state_atom(connections, &arguments.state_name)?;
+        return ewmh_change_state(ewmh, active_window, screen, ewmh_state_action(arguments.state_action), state);
+    }
+    let current_dimensions = info.geometry;
+    let work_area = get_work_area(connections, screen)?;
+    let is_fullscreen = info.states.contains(&ewmh.WM_STATE_FULLSCREEN());
+    let ratio = window_properties.get(&active_window).map_or(0, |prop| prop.ratio);
+    let stored_monitor = window_properties.get(&active_window).map_or(ALL_MONITORS, |prop| prop.monitor);
+    let stored_area = get_split_area(connections, screen, stored_monitor)?;
+    // Splits stay on the monitor the window was last split on unless told otherwise, as long
+    // as that monitor is still there
+    let monitor = match (request.target.monitor, stored_area) {
+        (ALL_MONITORS, Some(_)) => stored_monitor,
+        (ALL_MONITORS, None) => ALL_MONITORS,
+        (monitor, _) => match get_split_area(connections, screen, monitor)? {
+            Some(_) => monitor,
+            None => return Err(Error::invalid_command(&format!("no monitor {}", monitor))),
+        },
+    };
+    // Manual resizes are detected against where the window is split now, not where the
+    // request moves it
+    let split_area = stored_area.unwrap_or(work_area);
+    let splitleft_dimensions = fit_split(config, &info, split_area, State::SplitLeft, ratio);
+    let splitright_dimensions = fit_split(config, &info, split_area, State::SplitRight, ratio);
 
     #[cfg(feature = "debug")]
     println!("id: {}, cmd: {}, x: {}, y: {}, width: {}, height: {}",
-             active_window, message.get(COMMAND).unwrap(), window_x, window_y, window_width, window_height);
+             active_window, request.command.name(), current_dimensions.x, current_dimensions.y,
+             current_dimensions.width, current_dimensions.height);
 
     // Checks the current state of the window and stores dimensions if necessary
     let is_windowed_state = ( window_properties.get(&active_window).is_none()
                               || window_properties.get(&active_window).unwrap().state == State::Windowed )
-        && message.get(COMMAND).unwrap() != RESTORE;
+        && request.command != Command::Restore
+        && !is_fullscreen;
     if is_windowed_state {
-        let dim = Dimensions{x: window_x, y: window_y, width: window_width, height: window_height};
-        let prop = Properties{state: State::Windowed, dimensions:dim};
+        let prop = Properties::windowed(current_dimensions);
         window_properties.insert(active_window, prop);
     }
-    // Checks for manual resizes on a managed split window
-    let current_dimensions = Dimensions {
-        x: window_x,
-        y: window_y,
-        width: window_width,
-        height: window_height,
-    };
-    let splitleft_dimensions = Dimensions {
-        x: work_x,
-        y: work_y,
-        width: half_width, 
-        height: work_height,
-    };
-    let splitright_dimensions = Dimensions {
-        x: half_width as i16,
-        y: work_y,
-        width: half_width, 
-        height: work_height,
-    };
-    let _maximized_dimensions = Dimensions {
-        x: work_x,
-        y: work_y,
-        width: work_width, 
-        height: work_height,
-    };
+    // Checks for manual resizes on a managed split window, the split rectangles include the gaps
     // TODO fix work area dimensions for extended monitor setup
-    conditionally_store_dimensions(active_window, window_properties, current_dimensions.clone(), splitleft_dimensions, State::SplitLeft);
-    conditionally_store_dimensions(active_window, window_properties, current_dimensions.clone(), splitright_dimensions, State::SplitRight);
-    // conditionally_store_dimensions(active_window, window_properties, current_dimensions.clone(), maximized_dimensions, State::Maximized);
+    conditionally_store_dimensions(active_window, window_properties, current_dimensions, splitleft_dimensions, State::SplitLeft);
+    conditionally_store_dimensions(active_window, window_properties, current_dimensions, splitright_dimensions, State::SplitRight);
+    // conditionally_store_dimensions(active_window, window_properties, current_dimensions, work_area, State::Maximized);
+
+    // Leave fullscreen before anything else, toggling it off or restoring returns the
+    // window to whatever state it was in before going fullscreen
+    let mut command = request.command;
+    if is_fullscreen && leaves_fullscreen(command) {
+        // Fullscreened behind our back before we ever saw it windowed, so there are no
+        // dimensions to go back to and the work area is the best guess
+        if command != Command::Fullscreen && command != Command::Restore {
+            window_properties.entry(active_window).or_insert_with(|| Properties::windowed(work_area));
+        }
+        ewmh_fullscreen(ewmh, active_window, screen, ewmh::STATE_REMOVE)?;
+        if command == Command::Fullscreen || command == Command::Restore {
+            match window_properties.get_mut(&active_window) {
+                // Splits go back to the ratio they had rather than counting as a repeated split
+                Some(prop) if prop.previous == State::SplitLeft || prop.previous == State::SplitRight => {
+                    prop.state = prop.previous;
+                    return apply_split(connections, config, &info, prop);
+                },
+                Some(prop) => command = prop.previous.command(),
+                // Fullscreened behind our back, the window manager knows where it was
+                None => return Ok(()),
+            }
+        }
+    }
 
     // Process the command and alter the cached window state
-    match message.get(COMMAND).unwrap() {
-        RESTORE => {
+    match command {
+        Command::Restore => {
             ewmh_restore(ewmh, active_window, screen)?;
             match window_properties.get_mut(&active_window) {
                 Some(prop) => {
                     prop.state = State::Windowed;
-                    let dim = &prop.dimensions;
-                    move_resize(base, ewmh, active_window, dim.x, dim.y, dim.width, dim.height)?;
+                    move_resize(connections, active_window, screen, config.move_method, info.decorations, prop.dimensions)?;
                 },
-                None => return Err(GenericError::new("cannot find active window in memory")),
+                None => return Err(Error::window("cannot find active window in memory")),
             };
         },
-        SPLITLEFT => {
-            ewmh_restore(ewmh, active_window, screen)?;
-            match window_properties.get_mut(&active_window) {
-                Some(prop) => prop.state = State::SplitLeft,
-                None => return Err(GenericError::new("cannot find active window in memory")),
+        Command::SplitLeft | Command::SplitRight => {
+            let side = match command {
+                Command::SplitLeft => State::SplitLeft,
+                _ => State::SplitRight,
+            };
+            let ratio = request.arguments.ratio;
+            if ratio != CYCLE_RATIO && (ratio < 0 || ratio as usize >= config.ratios.len()) {
+                return Err(Error::invalid_command(&format!("no split ratio {}, {} are configured", ratio, config.ratios.len())));
             }
-            move_resize(base, ewmh, active_window, work_x, work_y, half_width, work_height)?;
-        },
-        SPLITRIGHT => {
             ewmh_restore(ewmh, active_window, screen)?;
-            match window_properties.get_mut(&active_window) {
-                Some(prop) => prop.state = State::SplitRight,
-                None => return Err(GenericError::new("cannot find active window in memory")),
-            }
-            move_resize(base, ewmh, active_window, half_width as i16, work_y, half_width, work_height)?;
+            let prop = match window_properties.get_mut(&active_window) {
+                Some(prop) => prop,
+                None => return Err(Error::window("cannot find active window in memory")),
+            };
+            // Splitting to the same side again cycles through the configured ratios
+            prop.ratio = match (ratio, prop.state == side) {
+                (CYCLE_RATIO, true) => (prop.ratio + 1) % config.ratios.len(),
+                (CYCLE_RATIO, false) => 0,
+                (ratio, _) => ratio as usize,
+            };
+            prop.state = side;
+            prop.monitor = monitor;
+            apply_split(connections, config, &info, prop)?;
         },
-        MAXIMIZE => {
+        Command::Maximize => {
             if let Some(prop) = window_properties.get_mut(&active_window) {
                 prop.state = State::Maximized;
             }
             ewmh_maximize(ewmh, active_window, screen)?;
         },
-        SAVE => {
-            let prop = Properties{state: State::Windowed, dimensions: current_dimensions};
+        Command::Fullscreen => {
+            match window_properties.get_mut(&active_window) {
+                Some(prop) => {
+                    // Our state may be stale if fullscreen was left behind our back
+                    if prop.state != State::Fullscreen {
+                        prop.previous = prop.state;
+                    }
+                    prop.state = State::Fullscreen;
+                },
+                None => return Err(Error::window("cannot find active window in memory")),
+            }
+            ewmh_fullscreen(ewmh, active_window, screen, ewmh::STATE_ADD)?;
+        },
+        Command::Minimize => {
+            match window_properties.get_mut(&active_window) {
+                Some(prop) => {
+                    if prop.state != State::Minimized {
+                        prop.before_minimize = prop.state;
+                    }
+                    prop.state = State::Minimized;
+                },
+                None => return Err(Error::window("cannot find active window in memory")),
+            }
+            minimized.retain(|window| *window != active_window);
+            minimized.push(active_window);
+            icccm_iconify(connections, active_window, screen)?;
+        },
+        Command::Decorate | Command::Undecorate | Command::ToggleDecorations => {
+            let decorated = match command {
+                Command::Decorate => true,
+                Command::Undecorate => false,
+                _ => !is_decorated(connections, active_window)?,
+            };
+            set_decorated(connections, active_window, decorated)?;
+            // Split windows have to cover the same area with the new frame extents
+            let prop = match window_properties.get(&active_window) {
+                Some(prop) if prop.state == State::SplitLeft || prop.state == State::SplitRight => prop,
+                _ => return Ok(()),
+            };
+            info.decorations = wait_for_decorations(connections, active_window, info.decorations)?;
+            apply_split(connections, config, &info, prop)?;
+        },
+        Command::Save => {
+            let dimensions = match request.arguments.geometry {
+                geometry if geometry.width == 0 || geometry.height == 0 => current_dimensions,
+                geometry => geometry,
+            };
+            let prop = Properties::windowed(dimensions);
             window_properties.insert(active_window, prop);
         },
-        _ => return Err(GenericError::new("invalid command")),
+        _ => return Err(Error::invalid_command("invalid command")),
+    }
+    Ok(())
+}
+
+fn send_reply(socket: &UnixDatagram, sender: &Sender, result: &Result<(), Error>) {
+    send_datagram(socket, sender, encode_response(&Response::new(result)));
+}
+
+fn send_datagram(socket: &UnixDatagram, sender: &Sender, reply: Result<Vec<u8>, Error>) {
+    // Clients sending from an unbound socket don't expect an answer
+    let sender_path = match &sender.path {
+        Some(path) => path,
+        None => return,
+    };
+    let reply_enc = match reply {
+        Ok(enc) => enc,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        },
+    };
+    if let Err(e) = socket.send_to(&reply_enc, sender_path) {
+        eprintln!("{}", e);
+    }
+}
+
+struct ServerArgs {
+    config_path: PathBuf,
+    display: String,
+    daemon: bool,
+}
+
+// The configured log file, daemons without one log next to the socket
+fn get_log_path(config: &Config, args: &ServerArgs) -> Result<Option<PathBuf>, Error> {
+    match &config.log.file {
+        Some(path) => Ok(Some(path.clone())),
+        None if args.daemon => Ok(Some(get_default_log_file(config, &args.display)?)),
+        None => Ok(None),
+    }
+}
+
+// Appends stderr to the log file
+fn redirect_log(path: Option<&Path>) -> Result<(), Error> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let file = match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(res) => res,
+        Err(e) => return Err(Error::io("open log file", e)),
+    };
+    if unsafe { libc::dup2(file.as_raw_fd(), libc::STDERR_FILENO) } < 0 {
+        return Err(Error::last_os_error("redirect log to file"));
     }
     Ok(())
 }
 
-fn exit() {
+// Moves a log that outgrew its limit to <file>.1 and starts a new one
+fn rotate_log(path: Option<&Path>, max_size: u64) -> Result<(), Error> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let size = match fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return Ok(()),
+    };
+    if max_size == 0 || size <= max_size {
+        return Ok(());
+    }
+    let mut rotated_path = path.as_os_str().to_os_string();
+    rotated_path.push(".1");
+    if let Err(e) = fs::rename(path, rotated_path) {
+        return Err(Error::io("rotate log file", e));
+    }
+    redirect_log(Some(path))
+}
+
+fn fork_to_background() -> Result<(), Error> {
+    match unsafe { libc::fork() } {
+        -1 => Err(Error::last_os_error("fork")),
+        0 => Ok(()),
+        _ => process::exit(0),
+    }
+}
+
+// Detaches from the terminal in a new session, the parent returns to the shell right away.
+// The working directory stays so relative paths from the command line keep working.
+fn daemonize() -> Result<(), Error> {
+    fork_to_background()?;
+    if unsafe { libc::setsid() } < 0 {
+        return Err(Error::last_os_error("setsid"));
+    }
+    // The second fork leaves a process that isn't a session leader and can't get a terminal back
+    fork_to_background()?;
+    let null = match OpenOptions::new().read(true).write(true).open("/dev/null") {
+        Ok(res) => res,
+        Err(e) => return Err(Error::io("open /dev/null", e)),
+    };
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO].iter() {
+        if unsafe { libc::dup2(null.as_raw_fd(), *fd) } < 0 {
+            return Err(Error::last_os_error("detach from terminal"));
+        }
+    }
+    Ok(())
+}
+
+// Window state and grabbed keys survive a reload, a broken config file keeps the old one.
+// Without an X connection the new key bindings are grabbed once we reconnect.
+// `log_path` is where stderr goes for the current config, it follows the new one
+fn reload(
+    x: Option<&mut XState>,
+    config: &mut Config,
+    log_path: &mut Option<PathBuf>,
+    args: &ServerArgs,
+) -> Result<(), Error> {
+    let new_config = Config::load(&args.config_path)?;
+    if new_config.socket_path != config.socket_path {
+        eprintln!("socket-path takes effect once the server is started again");
+    }
+    let new_log_path = get_log_path(&new_config, args)?;
+    if new_log_path != *log_path {
+        redirect_log(new_log_path.as_deref())?;
+        *log_path = new_log_path;
+    }
+    if let Some(x) = x {
+        ungrab_keys(x.connections.base(), &x.grabbed);
+        match grab_keys(x.connections.base(), &new_config.key_bindings()?) {
+            Ok(keys) => x.grabbed = keys,
+            Err(e) => {
+                x.grabbed = grab_keys(x.connections.base(), &config.key_bindings()?)?;
+                return Err(e);
+            },
+        }
+    }
+    *config = new_config;
+    Ok(())
+}
+
+// The client lists we've already seen, windows missing from them are new
+fn get_client_lists(connections: &XCBConnections) -> Result<HashMap<i32, Vec<xcb::xproto::Window>>, Error> {
+    let mut clients = HashMap::new();
+    for screen in 0..connections.base().get_setup().roots().count() as i32 {
+        clients.insert(screen, get_client_list(&connections.ewmh, screen)?);
+    }
+    Ok(clients)
+}
+
+// Runs the command of the first rule matching a window that just appeared
+fn apply_rules(
+    connections: &XCBConnections,
+    window_properties: &mut HashMap<xcb::xproto::Window, Properties>,
+    minimized: &mut Vec<xcb::xproto::Window>,
+    window: xcb::xproto::Window,
+    screen: i32,
+    config: &Config,
+) -> Result<(), Error> {
+    let base = connections.base();
+    let class = get_wm_class(base, window)?;
+    let title = get_window_title(connections, window)?;
+    let role = get_window_role(connections, window)?;
+    let rule = match config.rules.iter().find(|rule| rule.matches(&class, &title, &role)) {
+        Some(rule) => rule,
+        None => return Ok(()),
+    };
+    if config.log.verbose {
+        eprintln!("rule {} for window {}", rule.command, window);
+    }
+    let mut request = binding_request(&rule.command_words())?;
+    if let Some(monitor) = rule.monitor {
+        request.target.monitor = monitor as i32;
+    }
+    do_window_command(connections, window_properties, minimized, window, screen, request, config)
+}
+
+const PING_TIMEOUT_MS: u64 = 500;
+const REPLACE_POLL_ATTEMPTS: u32 = 50;
+const REPLACE_POLL_MS: u64 = 20;
+
+// Refuses to start next to a running server unless asked to replace it
+fn claim_socket(config: &Config, display: &str, replace: bool) -> Result<(), Error> {
+    let timeout = Duration::from_millis(PING_TIMEOUT_MS);
+    // Nobody answering means any socket left behind is stale and can be replaced
+    if request(config, display, &Request::new(Command::Ping), timeout).is_err() {
+        return Ok(());
+    }
+    if !replace {
+        return Err(Error::config(&format!("a server is already running on {}, use --replace to take over", display)));
+    }
+    request(config, display, &Request::new(Command::Quit), timeout)?;
+    // The old server removes its socket on the way out, which must happen before we bind ours
+    for _ in 0..REPLACE_POLL_ATTEMPTS {
+        if get_socket_file(config, display).is_err() {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(REPLACE_POLL_MS));
+    }
+    Err(Error::config("the running server didn't quit"))
+}
+
+fn exit(config: &Config, args: &ServerArgs, activated: bool) {
     // We should gracefully handle each operation so that everything gets executed
-    if let Err(e) = remove_socket_file() {
+    if !activated {
+        if let Err(e) = remove_socket_file(config, &args.display) {
+            eprintln!("{}", e);
+        }
+    }
+    if let Err(e) = remove_pid_file(config, &args.display) {
         eprintln!("{}", e);
     }
 }
 
-fn event_loop() -> Result<(), GenericError> {
+const RECONNECT_MIN_MS: u64 = 100;
+const RECONNECT_MAX_MS: u64 = 10000;
+
+// Everything that belongs to one X connection, rebuilt together when we reconnect
+struct XState {
+    connections: XCBConnections,
+    grabbed: Vec<GrabbedKey>,
+    clients: HashMap<i32, Vec<xcb::xproto::Window>>,
+    // Which X server the connection is to, see get_session_token
+    session: String,
+}
+
+fn connect(display: &str, config: &Config) -> Result<XState, Error> {
+    let connections = setup_connections(display)?;
+    // A conflicting key binding shouldn't keep us from managing windows
+    let grabbed = match config.key_bindings().and_then(|keys| grab_keys(connections.base(), &keys)) {
+        Ok(grabbed) => grabbed,
+        Err(e) if e.is_fatal() => return Err(e),
+        Err(e) => {
+            eprintln!("{}", e);
+            Vec::new()
+        },
+    };
+    watch_client_list(connections.base())?;
+    let clients = get_client_lists(&connections)?;
+    let session = get_session_token(&connections)?;
+    Ok(XState{connections, grabbed, clients, session})
+}
+
+// Forgets the windows that went away while we weren't connected, and all of them when the
+// X server itself was replaced since its windows only share our ids by accident
+fn revalidate_windows(
+    state: &XState,
+    session: &mut String,
+    window_properties: &mut HashMap<xcb::xproto::Window, Properties>,
+    minimized: &mut Vec<xcb::xproto::Window>,
+) {
+    if state.session != *session {
+        window_properties.clear();
+        minimized.clear();
+        session.clone_from(&state.session);
+    }
+    let connections = &state.connections;
+    window_properties.retain(|window, _| get_window_screen(connections.base(), *window).is_ok());
+    minimized.retain(|window| window_properties.contains_key(window));
+}
+
+// TOML needs string keys, so the windows are a list instead of a map
+#[derive(Default, Serialize, Deserialize)]
+struct SavedState {
+    session: String,
+    minimized: Vec<xcb::xproto::Window>,
+    windows: Vec<SavedWindow>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedWindow {
+    window: xcb::xproto::Window,
+    properties: Properties,
+}
+
+fn save_state(
+    path: &Path,
+    session: String,
+    window_properties: HashMap<xcb::xproto::Window, Properties>,
+    minimized: Vec<xcb::xproto::Window>,
+) -> Result<(), Error> {
+    let windows = window_properties.into_iter().map(|(window, properties)| SavedWindow{window, properties}).collect();
+    // Going through a toml::Value puts plain values before tables, which TOML requires
+    let contents = match toml::Value::try_from(SavedState{session, minimized, windows}).map(|value| value.to_string()) {
+        Ok(res) => res,
+        Err(e) => return Err(Error::config(&format!("serialize state file: {}", e))),
+    };
+    match fs::write(path, contents) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::io("write state file", e)),
+    }
+}
+
+// The file is removed once read, a server that dies without saving must not hand stale windows
+// to the next one
+fn load_state(path: &Path) -> Result<SavedState, Error> {
+    let contents = match fs::read_to_string(path) {
+        Ok(res) => res,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(SavedState::default()),
+        Err(e) => return Err(Error::io("read state file", e)),
+    };
+    if let Err(e) = fs::remove_file(path) {
+        return Err(Error::io("remove state file", e));
+    }
+    match toml::from_str(&contents) {
+        Ok(res) => Ok(res),
+        Err(e) => Err(Error::config(&format!("parse state file: {}", e))),
+    }
+}
+
+// Errors that only failed one command are logged, fatal ones are passed on to drop the connection
+fn log_error<T>(result: Result<T, Error>, events: &mut Vec<Event>) -> Result<Option<T>, Error> {
+    match result {
+        Ok(res) => Ok(Some(res)),
+        Err(e) if e.is_fatal() => Err(e),
+        Err(e) => {
+            eprintln!("{}", e);
+            events.push(Event::error(&e));
+            Ok(None)
+        },
+    }
+}
+
+// Drops a connection that died so the event loop reconnects right away
+fn handle_error(error: Error, x: &mut Option<XState>, reconnect_at: &mut Instant, events: &mut Vec<Event>) {
+    eprintln!("{}", error);
+    events.push(Event::error(&error));
+    if error.is_fatal() && x.is_some() {
+        *x = None;
+        *reconnect_at = Instant::now();
+    }
+}
+
+// Handles the key presses and new windows waiting on the connection
+fn handle_x_events(
+    x: &mut XState,
+    window_properties: &mut HashMap<xcb::xproto::Window, Properties>,
+    minimized: &mut Vec<xcb::xproto::Window>,
+    config: &mut Config,
+    log_path: &mut Option<PathBuf>,
+    args: &ServerArgs,
+    events: &mut Vec<Event>,
+) -> Result<(), Error> {
+    // Replies to our own requests can pull events off the connection, and those won't
+    // wake up poll
+    let mut bound_commands = Vec::new();
+    let mut changed_screens = Vec::new();
+    let mut focused_screens = Vec::new();
+    let mut work_area_changed = false;
+    while let Some(event) = x.connections.base().poll_for_event() {
+        match event.response_type() & !0x80 {
+            xcb::xproto::KEY_PRESS => {
+                let key_press: &xcb::xproto::KeyPressEvent = unsafe { xcb::cast_event(&event) };
+                if let Some(command) = find_key_command(&x.grabbed, key_press.detail(), key_press.state()) {
+                    bound_commands.push(command.to_vec());
+                }
+            },
+            xcb::xproto::PROPERTY_NOTIFY => {
+                let property: &xcb::xproto::PropertyNotifyEvent = unsafe { xcb::cast_event(&event) };
+                x.connections.property_changed(property.atom());
+                let ewmh = &x.connections.ewmh;
+                let screens = match property.atom() {
+                    atom if atom == ewmh.CLIENT_LIST() => &mut changed_screens,
+                    atom if atom == ewmh.ACTIVE_WINDOW() => &mut focused_screens,
+                    atom if atom == ewmh.WORKAREA() => {
+                        work_area_changed = true;
+                        continue;
+                    },
+                    _ => continue,
+                };
+                match get_window_screen(x.connections.base(), property.window()) {
+                    Ok(screen) if !screens.contains(&screen) => screens.push(screen),
+                    Ok(_) => (),
+                    Err(e) => eprintln!("{}", e),
+                }
+            },
+            _ => (),
+        }
+    }
+    if work_area_changed {
+        events.push(Event::new(EventKind::WorkArea));
+    }
+    for screen in focused_screens {
+        if let Some(window) = log_error(get_screen_active_window(&x.connections.ewmh, screen), events)? {
+            let state = window_properties.get(&window).map_or("", |prop| prop.state.name());
+            events.push(Event{window, state: state.to_string(), ..Event::new(EventKind::ActiveWindow)});
+        }
+    }
+    for screen in changed_screens {
+        let client_list = match log_error(get_client_list(&x.connections.ewmh, screen), events)? {
+            Some(res) => res,
+            None => continue,
+        };
+        let known = x.clients.insert(screen, client_list.clone()).unwrap_or_default();
+        for window in client_list.into_iter().filter(|window| !known.contains(window)) {
+            log_error(apply_rules(&x.connections, window_properties, minimized, window, screen, config), events)?;
+        }
+    }
+    for words in bound_commands {
+        if config.log.verbose {
+            eprintln!("key binding {}", words.join(" "));
+        }
+        let result = match words[0].as_str() {
+            RELOAD => reload(Some(x), config, log_path, args),
+            _ => binding_request(&words).and_then(|request| {
+                do_single_command(&x.connections, window_properties, minimized, request, config)
+            }),
+        };
+        log_error(result, events)?;
+    }
+    Ok(())
+}
+
+// Windows whose state differs from the last round, which catches every way of changing one
+fn window_state_events(
+    window_properties: &HashMap<xcb::xproto::Window, Properties>,
+    last_states: &mut HashMap<xcb::xproto::Window, State>,
+    events: &mut Vec<Event>,
+) {
+    for (window, prop) in window_properties.iter() {
+        if last_states.get(window) != Some(&prop.state) {
+            events.push(Event{window: *window, state: prop.state.name().to_string(), ..Event::new(EventKind::WindowState)});
+        }
+    }
+    *last_states = window_properties.iter().map(|(window, prop)| (*window, prop.state)).collect();
+}
+
+// Subscribers that went away are dropped, ones that don't keep up miss events instead of
+// blocking the server
+fn publish(socket: &UnixDatagram, subscribers: &mut Vec<PathBuf>, events: &mut Vec<Event>) {
+    for event in events.drain(..) {
+        let event_enc = match encode_event(&event) {
+            Ok(enc) => enc,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            },
+        };
+        subscribers.retain(|path| match socket.send_to(&event_enc, path) {
+            Ok(_) => true,
+            Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+        });
+    }
+}
+
+fn pollfd(fd: i32) -> libc::pollfd {
+    libc::pollfd{fd, events: libc::POLLIN, revents: 0}
+}
+
+fn event_loop(
+    args: &ServerArgs,
+    config: &mut Config,
+    mut log_path: Option<PathBuf>,
+    activated_socket: Option<UnixDatagram>,
+) -> Result<(), Error> {
+    let started = Instant::now();
+    let display = args.display.as_str();
     let mut window_properties: HashMap<xcb::xproto::Window, Properties> = HashMap::new();
-    let mut connections = setup_connections()?;
+    let mut minimized: Vec<xcb::xproto::Window> = Vec::new();
+    // Termination breaks out of the loop so the socket gets cleaned up, SIGHUP reloads the config
+    let signals = SignalFd::new(&[libc::SIGINT, libc::SIGTERM, libc::SIGHUP])?;
+    // Failing to connect at startup is most likely a wrong display, later failures are retried
+    let state = connect(display, config)?;
+    // Picks up the windows of the server we replaced or that was stopped before us, as long
+    // as it managed the same X server
+    let mut session = String::new();
+    match get_state_file(config, display).and_then(|path| load_state(&path)) {
+        Ok(saved) => {
+            session = saved.session;
+            window_properties.extend(saved.windows.into_iter().map(|saved| (saved.window, saved.properties)));
+            minimized = saved.minimized;
+        },
+        Err(e) => eprintln!("{}", e),
+    }
+    revalidate_windows(&state, &mut session, &mut window_properties, &mut minimized);
+    let mut x = Some(state);
+    let mut reconnect_at = Instant::now();
+    let mut reconnect_delay = Duration::from_millis(RECONNECT_MIN_MS);
 
-    let socket = bind_socket()?;
+    let socket = match activated_socket {
+        Some(socket) => socket,
+        None => bind_socket(config, display)?,
+    };
+    // Poll says when there's something to read, and a subscriber that stopped reading must not
+    // hang the server
+    if let Err(e) = socket.set_nonblocking(true) {
+        return Err(Error::io("make socket nonblocking", e));
+    }
+    let mut subscribers: Vec<PathBuf> = Vec::new();
+    let mut events: Vec<Event> = Vec::new();
+    let mut last_states: HashMap<xcb::xproto::Window, State> = HashMap::new();
+    // Hot reloading is a convenience, the server works without it
+    let watcher = match ConfigWatcher::new(&args.config_path) {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            eprintln!("{}, changes to the config need a reload", e);
+            None
+        },
+    };
 
-    loop {
-        let mut buf = vec![0; SOCKET_BUFFER_LEN];
-        let (size, _sender) = match socket.recv_from(&mut buf) {
+    // Whatever ends the loop, the windows are saved for the next server
+    let result = loop {
+        if let Err(e) = rotate_log(log_path.as_deref(), config.log.max_size) {
+            eprintln!("{}", e);
+        }
+        // The socket keeps answering while we wait for the X server, the delay doubles with
+        // every failed attempt
+        if x.is_none() && Instant::now() >= reconnect_at {
+            match connect(display, config) {
+                Ok(state) => {
+                    eprintln!("reconnected to {}", display);
+                    revalidate_windows(&state, &mut session, &mut window_properties, &mut minimized);
+                    reconnect_delay = Duration::from_millis(RECONNECT_MIN_MS);
+                    x = Some(state);
+                },
+                Err(e) => {
+                    eprintln!("{}, retrying in {}ms", e, reconnect_delay.as_millis());
+                    reconnect_at = Instant::now() + reconnect_delay;
+                    reconnect_delay = (reconnect_delay * 2).min(Duration::from_millis(RECONNECT_MAX_MS));
+                },
+            }
+        }
+        let result = match &mut x {
+            Some(state) => handle_x_events(state, &mut window_properties, &mut minimized, config, &mut log_path, args, &mut events),
+            None => Ok(()),
+        };
+        if let Err(e) = result {
+            handle_error(e, &mut x, &mut reconnect_at, &mut events);
+        }
+        if let Some(state) = &x {
+            if let Err(e) = state.connections.base().has_error() {
+                handle_error(Error::connection("X connection", Some(e)), &mut x, &mut reconnect_at, &mut events);
+            }
+        }
+        window_state_events(&window_properties, &mut last_states, &mut events);
+        publish(&socket, &mut subscribers, &mut events);
+
+        // poll skips negative fds, so there's nothing to wake up for while disconnected
+        let x_fd = x.as_ref().map_or(-1, |state| state.connections.base().as_raw_fd());
+        let timeout = match x {
+            Some(_) => -1,
+            None => reconnect_at.saturating_duration_since(Instant::now()).as_millis() as libc::c_int,
+        };
+        let mut fds = vec![pollfd(socket.as_raw_fd()), pollfd(x_fd), pollfd(signals.fd())];
+        if let Some(watcher) = &watcher {
+            fds.push(pollfd(watcher.fd()));
+        }
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            break Err(Error::last_os_error("poll"));
+        }
+        let mut config_changed = false;
+        if fds[2].revents != 0 {
+            let pending = signals.pending();
+            if pending.iter().any(|signal| *signal == libc::SIGINT || *signal == libc::SIGTERM) {
+                break Ok(());
+            }
+            config_changed = pending.contains(&libc::SIGHUP);
+        }
+        if let Some(watcher) = &watcher {
+            if fds[3].revents != 0 && watcher.changed() {
+                config_changed = true;
+            }
+        }
+        if config_changed {
+            match reload(x.as_mut(), config, &mut log_path, args) {
+                Ok(_) => eprintln!("reloaded {}", args.config_path.display()),
+                Err(e) => handle_error(e, &mut x, &mut reconnect_at, &mut events),
+            }
+        }
+        if fds[0].revents & libc::POLLIN == 0 {
+            continue;
+        }
+
+        let datagram_size = match peek_datagram_size(&socket, "receive from socket") {
+            Ok(size) => size,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            },
+        };
+        // Oversized datagrams are still taken off the socket, cut short, so the sender gets an answer
+        let mut buf = vec![0; datagram_size.min(config.buffer_len)];
+        let (size, sender) = match recv_with_credentials(&socket, &mut buf) {
             Ok((sz, sndr)) => (sz, sndr),
             Err(e) => {
                 eprintln!("{}", e);
                 continue;
             },
         };
-        let message = decode_data(&buf[0..size])?;
-
-        match message.get(COMMAND).unwrap() {
-            RESTART => {
-                connections = setup_connections()?;
+        // Other users can reach the socket when its path is configured somewhere shared
+        if !sender.is_same_user() {
+            eprintln!("ignoring message from uid {}", sender.uid);
+            continue;
+        }
+        if datagram_size > config.buffer_len {
+            let error = Error::invalid_command(&format!(
+                "message of {} bytes is larger than buffer-len ({})", datagram_size, config.buffer_len));
+            eprintln!("{}", error);
+            send_reply(&socket, &sender, &Err(error));
+            continue;
+        }
+        let request = match decode_request(&buf[0..size]) {
+            Ok(request) => request,
+            Err(e) => {
+                eprintln!("{}", e);
+                // Clients from before the protocol was versioned only understand their own replies
+                match is_legacy_request(&buf[0..size]) {
+                    true => send_datagram(&socket, &sender, encode_legacy_error(&Error::protocol(&format!(
+                        "sssc is too old for the server, which speaks protocol version {}", PROTOCOL_VERSION)))),
+                    false => send_reply(&socket, &sender, &Err(e)),
+                }
                 continue;
             },
-            QUIT => break,
-            _ => {
-                if let Err(e) = do_single_command(&connections, &mut window_properties, message) {
+        };
+        if config.log.verbose {
+            eprintln!("command {}", request.command.name());
+        }
+
+        match request.command {
+            Command::Restart => {
+                // A failed restart keeps whatever connection we still have
+                let result = connect(display, config).map(|state| {
+                    revalidate_windows(&state, &mut session, &mut window_properties, &mut minimized);
+                    reconnect_delay = Duration::from_millis(RECONNECT_MIN_MS);
+                    x = Some(state);
+                });
+                send_reply(&socket, &sender, &result);
+                if let Err(e) = result {
                     eprintln!("{}", e);
                 }
             },
+            Command::Ping => send_reply(&socket, &sender, &Ok(())),
+            Command::Pid => {
+                let reply = Response{pid: process::id(), ..Response::new(&Ok(()))};
+                send_datagram(&socket, &sender, encode_response(&reply));
+            },
+            Command::Uptime => {
+                let reply = Response{uptime: started.elapsed().as_secs(), ..Response::new(&Ok(()))};
+                send_datagram(&socket, &sender, encode_response(&reply));
+            },
+            Command::Reload => {
+                let result = reload(x.as_mut(), config, &mut log_path, args);
+                send_reply(&socket, &sender, &result);
+                if let Err(e) = result {
+                    handle_error(e, &mut x, &mut reconnect_at, &mut events);
+                }
+            },
+            Command::Quit => {
+                send_reply(&socket, &sender, &Ok(()));
+                break Ok(());
+            },
+            Command::Subscribe => {
+                let result = match &sender.path {
+                    Some(path) => {
+                        if !subscribers.contains(path) {
+                            subscribers.push(path.clone());
+                        }
+                        Ok(())
+                    },
+                    None => Err(Error::invalid_command("subscribing needs a bound socket to send events to")),
+                };
+                send_reply(&socket, &sender, &result);
+            },
+            _ => {
+                // Events that came in while we were waiting can make the cached work areas stale
+                let result = match &mut x {
+                    Some(state) => handle_x_events(state, &mut window_properties, &mut minimized, config, &mut log_path, args, &mut events).and_then(|_| {
+                        do_single_command(&state.connections, &mut window_properties, &mut minimized, request, config)
+                    }),
+                    None => Err(Error::connection("waiting to reconnect to the X server", None)),
+                };
+                send_reply(&socket, &sender, &result);
+                if let Err(e) = result {
+                    handle_error(e, &mut x, &mut reconnect_at, &mut events);
+                }
+            },
         }
+    };
+    if let Err(e) = get_state_file(config, display).and_then(|path| save_state(&path, session, window_properties, minimized)) {
+        eprintln!("{}", e);
     }
-    Ok(())
+    result
 }
 
 fn main() {
-    if let Err(e) = event_loop() {
+    let matches = App::new("Stateful Split Screen Server")
+        .arg(Arg::with_name("config")
+             .long("config")
+             .takes_value(true)
+             .help("Config file to use instead of ~/.config/sss/config.toml"))
+        .arg(Arg::with_name("display")
+             .long("display")
+             .takes_value(true)
+             .help("X display to manage instead of $DISPLAY"))
+        .arg(Arg::with_name("replace")
+             .long("replace")
+             .help("Ask an already running server to quit and take its place"))
+        .arg(Arg::with_name("daemon")
+             .long("daemon")
+             .help("Detach from the terminal and log to a file next to the socket unless log.file is set"))
+        .get_matches();
+    let display = match get_display(matches.value_of("display")) {
+        Ok(display) => display,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        },
+    };
+    let config_path = match matches.value_of("config") {
+        Some(path) => PathBuf::from(path),
+        None => match get_config_file() {
+            Ok(path) => path,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            },
+        },
+    };
+    let mut config = match Config::load(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        },
+    };
+    let args = ServerArgs{config_path, display, daemon: matches.is_present("daemon")};
+    // An activated socket belongs to systemd, which also makes sure we're the only server
+    let activated_socket = match get_activated_socket() {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        },
+    };
+    let activated = activated_socket.is_some();
+    if !activated {
+        if let Err(e) = claim_socket(&config, &args.display, matches.is_present("replace")) {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    // Startup problems are still printed to the terminal, everything after goes to the log
+    if args.daemon {
+        if let Err(e) = daemonize() {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    // Worked out once, rotating the log on every event must not touch the filesystem
+    let log_path = match get_log_path(&config, &args) {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("{}", e);
+            None
+        },
+    };
+    if let Err(e) = redirect_log(log_path.as_deref()) {
+        eprintln!("{}", e);
+    }
+    if let Err(e) = write_pid_file(&config, &args.display) {
+        eprintln!("{}", e);
+    }
+    if let Err(e) = event_loop(&args, &mut config, log_path, activated_socket) {
         eprintln!("{}", e);
     }
-    exit();
+    exit(&config, &args, activated);
 }